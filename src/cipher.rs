@@ -0,0 +1,129 @@
+//! Encryption of target URLs, so that signed URLs don't reveal their source.
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::Key;
+
+/// Length of the XChaCha20-Poly1305 nonce, which prefixes the ciphertext.
+const NONCE_LENGTH: usize = 24;
+
+/// Cipher of target URLs.
+///
+/// Nonces are derived from the plaintext, so that a given target URL always
+/// encrypts to the same ciphertext and signed URLs remain cacheable. This
+/// reveals whether two URLs share a target, but nothing of the target itself.
+#[derive(Clone)]
+pub(crate) struct TargetCipher {
+    cipher: XChaCha20Poly1305,
+    nonce_key: [u8; 32],
+}
+
+impl std::fmt::Debug for TargetCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TargetCipher").finish()
+    }
+}
+
+impl TargetCipher {
+    /// Creates a cipher with subkeys derived from `key`.
+    pub(crate) fn new(key: &Key) -> Self {
+        let encryption_key = derive(key, b"tower-image-xform target encryption");
+        Self {
+            cipher: XChaCha20Poly1305::new(&encryption_key.into()),
+            nonce_key: derive(key, b"tower-image-xform target nonce"),
+        }
+    }
+
+    /// Encrypts `target`, returning the nonce and ciphertext as unpadded
+    /// base64url.
+    pub(crate) fn encrypt(&self, target: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC can take key of any size");
+        mac.update(target.as_bytes());
+        let nonce = XNonce::clone_from_slice(&mac.finalize().into_bytes()[..NONCE_LENGTH]);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, target.as_bytes())
+            .expect("Encryption of a target URL cannot fail");
+
+        URL_SAFE_NO_PAD.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a target encrypted by [`TargetCipher::encrypt`].
+    ///
+    /// Returns `None` when `encrypted` is malformed or was not encrypted with
+    /// this cipher's key.
+    pub(crate) fn decrypt(&self, encrypted: &str) -> Option<String> {
+        let encrypted = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if encrypted.len() < NONCE_LENGTH {
+            return None;
+        }
+
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()?;
+
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+fn derive(key: &Key, label: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.as_slice())
+        .expect("HMAC can take key of any size");
+    mac.update(label);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = "https://example.com/image.png";
+
+    #[test]
+    fn round_trips_targets() {
+        let cipher = TargetCipher::new(&Key::generate());
+
+        let encrypted = cipher.encrypt(TARGET);
+
+        assert!(!encrypted.contains("example"));
+        assert_eq!(cipher.encrypt(TARGET), encrypted);
+        assert_eq!(cipher.decrypt(&encrypted).as_deref(), Some(TARGET));
+    }
+
+    #[test]
+    fn rejects_tampered_targets() {
+        let cipher = TargetCipher::new(&Key::generate());
+        let mut encrypted = URL_SAFE_NO_PAD.decode(cipher.encrypt(TARGET)).unwrap();
+
+        *encrypted.last_mut().unwrap() ^= 1;
+
+        assert_eq!(cipher.decrypt(&URL_SAFE_NO_PAD.encode(encrypted)), None);
+    }
+
+    #[test]
+    fn rejects_targets_of_other_keys() {
+        let encrypted = TargetCipher::new(&Key::generate()).encrypt(TARGET);
+
+        assert_eq!(
+            TargetCipher::new(&Key::generate()).decrypt(&encrypted),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_targets() {
+        let cipher = TargetCipher::new(&Key::generate());
+
+        assert_eq!(cipher.decrypt("not base64!"), None);
+        assert_eq!(cipher.decrypt("c2hvcnQ"), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Conversion of embedded colour profiles to sRGB.
+use std::sync::{Arc, OnceLock};
+
+use image::{DynamicImage, ImageBuffer};
+use moxcms::{CmsError, ColorProfile, DataColorSpace, Layout, TransformExecutor, TransformOptions};
+
+/// Returns the encoded sRGB ICC profile.
+pub(crate) fn srgb_profile() -> Option<Vec<u8>> {
+    static SRGB_PROFILE: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+    SRGB_PROFILE
+        .get_or_init(|| {
+            ColorProfile::new_srgb()
+                .encode()
+                .inspect_err(|err| tracing::error!(err = ?err, "could not encode sRGB profile"))
+                .ok()
+        })
+        .clone()
+}
+
+/// Converts the pixels of `image` from the colour space described by
+/// `icc_profile` to sRGB.
+///
+/// Images with more than 8 bits per channel are converted at 16 bits per
+/// channel. Returns `None` when the profile cannot be parsed or does not
+/// describe an RGB colour space, in which case the image should be left
+/// untouched.
+pub(crate) fn convert_to_srgb(image: &DynamicImage, icc_profile: &[u8]) -> Option<DynamicImage> {
+    let source = ColorProfile::new_from_slice(icc_profile)
+        .inspect_err(|err| tracing::warn!(err = ?err, "could not parse ICC profile"))
+        .ok()?;
+
+    if source.color_space != DataColorSpace::Rgb {
+        tracing::debug!(color_space = ?source.color_space, "skipping non-RGB ICC profile");
+        return None;
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    let (width, height) = (image.width(), image.height());
+    let options = TransformOptions::default();
+    let has_alpha = image.color().has_alpha();
+    let layout = if has_alpha { Layout::Rgba } else { Layout::Rgb };
+
+    if image.color().bytes_per_pixel() / image.color().channel_count() > 1 {
+        let transform = source.create_transform_16bit(layout, &srgb, layout, options);
+        if has_alpha {
+            let pixels = transform_pixels(transform, &image.to_rgba16())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba16)
+        } else {
+            let pixels = transform_pixels(transform, &image.to_rgb16())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb16)
+        }
+    } else {
+        let transform = source.create_transform_8bit(layout, &srgb, layout, options);
+        if has_alpha {
+            let pixels = transform_pixels(transform, &image.to_rgba8())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+        } else {
+            let pixels = transform_pixels(transform, &image.to_rgb8())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+    }
+}
+
+fn transform_pixels<T: Copy + Default>(
+    transform: Result<Arc<dyn TransformExecutor<T> + Send + Sync>, CmsError>,
+    pixels: &[T],
+) -> Option<Vec<T>> {
+    let transform = transform
+        .inspect_err(|err| tracing::warn!(err = ?err, "could not create colour transform"))
+        .ok()?;
+
+    let mut transformed = vec![T::default(); pixels.len()];
+    transform
+        .transform(pixels, &mut transformed)
+        .inspect_err(|err| tracing::warn!(err = ?err, "could not transform colours"))
+        .ok()?;
+
+    Some(transformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba};
+
+    use super::*;
+
+    fn display_p3() -> Vec<u8> {
+        ColorProfile::new_display_p3().encode().unwrap()
+    }
+
+    #[test]
+    fn converts_8_bit_images() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([0, 255, 0])));
+
+        let converted = convert_to_srgb(&image, &display_p3()).unwrap();
+
+        assert!(matches!(converted, DynamicImage::ImageRgb8(_)));
+        // Display P3 green lies outside of sRGB, so it's clipped.
+        assert_eq!(converted.to_rgb8().get_pixel(0, 0)[1], 255);
+    }
+
+    #[test]
+    fn keeps_16_bit_depth() {
+        let image = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            2,
+            2,
+            Rgba([1000, 30000, 50000, 65535]),
+        ));
+
+        let converted = convert_to_srgb(&image, &display_p3()).unwrap();
+
+        let DynamicImage::ImageRgba16(converted) = converted else {
+            panic!("expected a 16-bit image");
+        };
+        assert_eq!(converted.get_pixel(0, 0)[3], 65535);
+        assert_ne!(converted.get_pixel(0, 0)[1] % 257, 0);
+    }
+
+    #[test]
+    fn skips_unparseable_profiles() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+
+        assert!(convert_to_srgb(&image, b"not a profile").is_none());
+    }
+
+    #[test]
+    fn skips_non_rgb_profiles() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+        let gray = ColorProfile::new_gray_with_gamma(2.2).encode().unwrap();
+
+        assert_eq!(
+            ColorProfile::new_from_slice(&gray).unwrap().color_space,
+            DataColorSpace::Gray
+        );
+        assert!(convert_to_srgb(&image, &gray).is_none());
+    }
+}
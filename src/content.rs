@@ -0,0 +1,150 @@
+//! Classification of decoded images by their content, used to choose between
+//! lossy and lossless output formats.
+use std::collections::HashSet;
+
+use image::{DynamicImage, GenericImageView};
+
+/// Images are sampled on a grid of at most this many points per axis.
+const MAX_SAMPLES_PER_AXIS: u32 = 256;
+
+/// Images with at most this many distinct colours are considered graphics.
+const MAX_GRAPHIC_COLORS: usize = 256;
+
+/// Images in which at least this share of neighbouring samples are identical
+/// are considered graphics; photos rarely have exactly flat regions.
+const MIN_GRAPHIC_FLAT_RATIO: f64 = 0.5;
+
+/// Broad kind of content an image holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImageContent {
+    /// Flat colours and hard edges, such as screenshots, icons and
+    /// illustrations, which lossy encoding visibly degrades.
+    Graphic,
+
+    /// Continuous tones, such as photographs, which lossy encoding compresses
+    /// far better.
+    Photo,
+}
+
+/// Summary of an image's content.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ContentAnalysis {
+    pub(crate) content: ImageContent,
+
+    /// Whether any pixel is not fully opaque.
+    pub(crate) has_alpha: bool,
+}
+
+/// Analyses `image` by sampling its pixels on a regular grid.
+pub(crate) fn analyze(image: &DynamicImage) -> ContentAnalysis {
+    let (width, height) = image.dimensions();
+    let step_x = width.div_ceil(MAX_SAMPLES_PER_AXIS).max(1);
+    let step_y = height.div_ceil(MAX_SAMPLES_PER_AXIS).max(1);
+
+    let mut colors = HashSet::new();
+    let mut has_alpha = false;
+    let (mut flat, mut pairs) = (0u64, 0u64);
+
+    for y in (0..height).step_by(step_y as usize) {
+        let mut previous = None;
+
+        for x in (0..width).step_by(step_x as usize) {
+            let pixel = image.get_pixel(x, y).0;
+            has_alpha |= pixel[3] < u8::MAX;
+
+            if colors.len() <= MAX_GRAPHIC_COLORS {
+                colors.insert(pixel);
+            }
+
+            if let Some(previous) = previous {
+                pairs += 1;
+                if previous == pixel {
+                    flat += 1;
+                }
+            }
+            previous = Some(pixel);
+        }
+    }
+
+    let flat_ratio = if pairs == 0 {
+        1.0
+    } else {
+        flat as f64 / pairs as f64
+    };
+
+    let content = if colors.len() <= MAX_GRAPHIC_COLORS || flat_ratio >= MIN_GRAPHIC_FLAT_RATIO {
+        ImageContent::Graphic
+    } else {
+        ImageContent::Photo
+    };
+
+    ContentAnalysis { content, has_alpha }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use image::{ImageBuffer, Rgb, RgbImage, Rgba};
+
+    use super::*;
+
+    /// Returns an image of smooth gradients with sensor-like noise.
+    pub(crate) fn photo() -> DynamicImage {
+        let mut state = 0x9e37_79b9_u32;
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(300, 200, |x, y| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = (state % 9) as u8;
+            Rgb([
+                (x * 200 / 300) as u8 + noise,
+                (y * 200 / 200) as u8 + noise,
+                ((x + y) * 100 / 500) as u8 + noise,
+            ])
+        }))
+    }
+
+    /// Returns black lines on a white background.
+    pub(crate) fn line_art() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(300, 200, |x, y| {
+            if x % 40 < 2 || y % 25 < 2 || x == y {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        }))
+    }
+
+    #[test]
+    fn classifies_photos() {
+        let analysis = analyze(&photo());
+
+        assert_eq!(analysis.content, ImageContent::Photo);
+        assert!(!analysis.has_alpha);
+    }
+
+    #[test]
+    fn classifies_line_art() {
+        assert_eq!(analyze(&line_art()).content, ImageContent::Graphic);
+    }
+
+    #[test]
+    fn classifies_flat_colours() {
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([12, 34, 56])));
+        let pixel = DynamicImage::ImageRgb8(RgbImage::new(1, 1));
+
+        assert_eq!(analyze(&flat).content, ImageContent::Graphic);
+        assert_eq!(analyze(&pixel).content, ImageContent::Graphic);
+    }
+
+    #[test]
+    fn detects_transparency() {
+        let mut image = photo().to_rgba8();
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 254]));
+        let image = DynamicImage::ImageRgba8(image);
+
+        let analysis = analyze(&image);
+
+        assert_eq!(analysis.content, ImageContent::Photo);
+        assert!(analysis.has_alpha);
+    }
+}
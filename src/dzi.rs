@@ -0,0 +1,705 @@
+//! Deep Zoom images, as displayed by viewers such as OpenSeadragon.
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures_util::lock::Mutex as AsyncMutex;
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use url::Url;
+
+use crate::source::{self, with_trailing_slash};
+
+/// Default width and height of tiles, in pixels, excluding the overlap.
+const DEFAULT_TILE_SIZE: u32 = 254;
+
+/// Default overlap of adjacent tiles, in pixels.
+const DEFAULT_OVERLAP: u32 = 1;
+
+/// Default capacity of the cache of downscaled levels, in bytes.
+const DEFAULT_CACHE_CAPACITY: u64 = 512 * 1024 * 1024;
+
+/// Default time for which the levels of an image are cached.
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Configuration of Deep Zoom images.
+///
+/// Images are described by `{name}.dzi` and tiled as
+/// `{name}_files/{level}/{column}_{row}.{format}`, where the name is resolved
+/// against the source URL. Tiles are computed on demand, and the levels of the
+/// pyramid they're cut from are cached, up to the cache capacity, so that
+/// subsequent tiles of a level don't require the source image to be fetched
+/// and decoded again.
+///
+/// Cached images are neither revalidated nor invalidated when their source
+/// changes. Instead, they expire after the cache TTL, after which the source
+/// is fetched and decoded again. Descriptors merely read the dimensions of the
+/// source, without decoding it, unless it's cached.
+///
+/// Since sources are configured rather than requested, they're decoded
+/// without the memory limits which otherwise apply, so that gigapixel images
+/// may be served. Each source is decoded by one request at a time, which the
+/// others wait for, and the cache capacity should hold its full resolution
+/// level, which is evicted only after the levels downscaled from it.
+///
+/// # Example
+///
+/// ```rust
+/// use image::ImageFormat;
+/// use tower_image_xform::Dzi;
+///
+/// let dzi = Dzi::new("https://archive.example.com/scans/".parse().unwrap())
+///     .set_tile_size(510)
+///     .set_overlap(1)
+///     .set_format(ImageFormat::Png);
+/// ```
+#[derive(Clone)]
+pub struct Dzi {
+    source: Url,
+    tile_size: u32,
+    overlap: u32,
+    format: ImageFormat,
+    cache: Arc<Mutex<LevelCache>>,
+    loading: Arc<Mutex<HashMap<Url, Arc<AsyncMutex<()>>>>>,
+}
+
+impl Dzi {
+    /// Create a new [`Dzi`], given the URL names are resolved against.
+    pub fn new(source: Url) -> Self {
+        Self {
+            source: with_trailing_slash(source),
+            tile_size: DEFAULT_TILE_SIZE,
+            overlap: DEFAULT_OVERLAP,
+            format: ImageFormat::Jpeg,
+            cache: Arc::new(Mutex::new(LevelCache::new(
+                DEFAULT_CACHE_CAPACITY,
+                DEFAULT_CACHE_TTL,
+            ))),
+            loading: Arc::default(),
+        }
+    }
+
+    /// Set the width and height of tiles, in pixels, excluding the overlap.
+    ///
+    /// Defaults to 254.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tile_size` is zero.
+    pub fn set_tile_size(self, tile_size: u32) -> Self {
+        assert!(tile_size > 0, "tile size must be greater than zero");
+        Self { tile_size, ..self }
+    }
+
+    /// Set the overlap of adjacent tiles, in pixels.
+    ///
+    /// Defaults to 1.
+    pub fn set_overlap(self, overlap: u32) -> Self {
+        Self { overlap, ..self }
+    }
+
+    /// Set the format of tiles, which must be among the supported image types.
+    ///
+    /// Defaults to JPEG.
+    ///
+    /// # Panics
+    ///
+    /// [`ImageTransformerBuilder::build`](crate::ImageTransformerBuilder::build)
+    /// panics if the format is not among the supported image types.
+    pub fn set_format(self, format: ImageFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Set the capacity of the cache of downscaled levels, in bytes of decoded
+    /// pixels.
+    ///
+    /// Defaults to 512 MiB.
+    pub fn set_cache_capacity(self, cache_capacity: u64) -> Self {
+        let cache_ttl = self
+            .cache
+            .lock()
+            .expect("cache lock must not be poisoned")
+            .ttl;
+        Self {
+            cache: Arc::new(Mutex::new(LevelCache::new(cache_capacity, cache_ttl))),
+            ..self
+        }
+    }
+
+    /// Set the time for which the levels of an image are cached, after which
+    /// its source is fetched and decoded again, so that changes to it are
+    /// picked up.
+    ///
+    /// Defaults to one hour.
+    pub fn set_cache_ttl(self, cache_ttl: Duration) -> Self {
+        let cache_capacity = self
+            .cache
+            .lock()
+            .expect("cache lock must not be poisoned")
+            .capacity;
+        Self {
+            cache: Arc::new(Mutex::new(LevelCache::new(cache_capacity, cache_ttl))),
+            ..self
+        }
+    }
+
+    pub(crate) const fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Resolves the path of a request, relative to where the service is
+    /// mounted.
+    pub(crate) fn resolve(&self, path: &str) -> Result<DziRequest, &'static str> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+
+        if let Some(name) = path.strip_suffix(".dzi") {
+            return Ok(DziRequest::Descriptor {
+                target: self.target(name)?,
+            });
+        }
+
+        let (name, tile) = path.rsplit_once("_files/").ok_or("path is invalid")?;
+        let (level, tile) = tile.split_once('/').ok_or("path is invalid")?;
+        let (position, format) = tile.rsplit_once('.').ok_or("format is required")?;
+        let (column, row) = position.split_once('_').ok_or("tile is invalid")?;
+
+        if ImageFormat::from_extension(format) != Some(self.format) {
+            return Err("format is not the configured format");
+        }
+
+        Ok(DziRequest::Tile {
+            target: self.target(name)?,
+            level: level
+                .parse()
+                .ok()
+                .filter(|level| *level < u32::BITS)
+                .ok_or("level is invalid")?,
+            column: column.parse().map_err(|_| "column is invalid")?,
+            row: row.parse().map_err(|_| "row is invalid")?,
+        })
+    }
+
+    /// Returns the `.dzi` descriptor of an image.
+    pub(crate) fn descriptor(&self, (width, height): (u32, u32)) -> String {
+        let format = self
+            .format
+            .extensions_str()
+            .first()
+            .copied()
+            .unwrap_or_default();
+        format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Format="{}" Overlap="{}" TileSize="{}">"#,
+                r#"<Size Width="{}" Height="{}"/>"#,
+                r#"</Image>"#,
+            ),
+            format, self.overlap, self.tile_size, width, height
+        )
+    }
+
+    /// Returns the cached level of an image nearest to `level`, from which it
+    /// may be downscaled, if any.
+    pub(crate) fn cached_level(
+        &self,
+        target: &Url,
+        level: u32,
+    ) -> Option<(u32, Arc<DynamicImage>)> {
+        self.cache
+            .lock()
+            .expect("cache lock must not be poisoned")
+            .get(target, level)
+    }
+
+    /// Returns the dimensions of an image which is cached.
+    pub(crate) fn cached_dimensions(&self, target: &Url) -> Option<(u32, u32)> {
+        self.cache
+            .lock()
+            .expect("cache lock must not be poisoned")
+            .dimensions(target)
+    }
+
+    /// Returns the cached level of an image nearest to `level`, or else loads
+    /// its source via `load`.
+    ///
+    /// Sources are loaded by one caller at a time, so that concurrent requests
+    /// wait for the first to load it rather than decoding it as well.
+    pub(crate) async fn level_or_load<F, Fut, E>(
+        &self,
+        target: &Url,
+        level: u32,
+        load: F,
+    ) -> Result<(u32, Arc<DynamicImage>), E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(u32, Arc<DynamicImage>), E>>,
+    {
+        if let Some(cached_level) = self.cached_level(target, level) {
+            return Ok(cached_level);
+        }
+
+        let lock = LoadingLock::new(self, target);
+        let _guard = lock.lock.lock().await;
+
+        // Another request may have loaded the source while waiting.
+        match self.cached_level(target, level) {
+            Some(cached_level) => Ok(cached_level),
+            None => load().await,
+        }
+    }
+
+    /// Cuts a tile from `level` of an image, downscaling it from the given
+    /// higher level and caching every level on the way.
+    ///
+    /// Returns `None` when the tile lies outside of the pyramid.
+    pub(crate) fn tile(
+        &self,
+        target: &Url,
+        (mut current_level, mut image): (u32, Arc<DynamicImage>),
+        level: u32,
+        column: u32,
+        row: u32,
+    ) -> Option<DynamicImage> {
+        if level > current_level {
+            return None;
+        }
+
+        while current_level > level {
+            current_level -= 1;
+            image = Arc::new(image.resize_exact(
+                image.width().div_ceil(2),
+                image.height().div_ceil(2),
+                FilterType::Triangle,
+            ));
+            self.cache
+                .lock()
+                .expect("cache lock must not be poisoned")
+                .insert(target, current_level, Arc::clone(&image));
+        }
+
+        let x = u64::from(column) * u64::from(self.tile_size);
+        let y = u64::from(row) * u64::from(self.tile_size);
+        if x >= u64::from(image.width()) || y >= u64::from(image.height()) {
+            return None;
+        }
+
+        // Tiles extend into their neighbours by the overlap, on every side
+        // which has a neighbour.
+        let (x, y) = (x as u32, y as u32);
+        let left = x.saturating_sub(self.overlap);
+        let top = y.saturating_sub(self.overlap);
+        let right = x
+            .saturating_add(self.tile_size)
+            .saturating_add(self.overlap)
+            .min(image.width());
+        let bottom = y
+            .saturating_add(self.tile_size)
+            .saturating_add(self.overlap)
+            .min(image.height());
+
+        Some(image.crop_imm(left, top, right - left, bottom - top))
+    }
+
+    /// Caches the full resolution level of an image.
+    pub(crate) fn insert_source(
+        &self,
+        target: &Url,
+        image: DynamicImage,
+    ) -> (u32, Arc<DynamicImage>) {
+        let level = max_level((image.width(), image.height()));
+        let image = Arc::new(image);
+        self.cache
+            .lock()
+            .expect("cache lock must not be poisoned")
+            .insert_source(target, level, Arc::clone(&image));
+        (level, image)
+    }
+
+    fn target(&self, name: &str) -> Result<Url, &'static str> {
+        if name.is_empty() {
+            return Err("name is required");
+        }
+
+        source::resolve(&self.source, name).ok_or("name is not allowed")
+    }
+}
+
+impl std::fmt::Debug for Dzi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dzi")
+            .field("source", &self.source)
+            .field("tile_size", &self.tile_size)
+            .field("overlap", &self.overlap)
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Request of a Deep Zoom image.
+#[derive(Debug)]
+pub(crate) enum DziRequest {
+    /// Request of the `.dzi` descriptor of an image.
+    Descriptor { target: Url },
+
+    /// Request of a tile of an image.
+    Tile {
+        target: Url,
+        level: u32,
+        column: u32,
+        row: u32,
+    },
+}
+
+/// Returns the level of the pyramid at which an image has its full
+/// resolution, i.e. the number of times it's halved until it's a single pixel.
+fn max_level((width, height): (u32, u32)) -> u32 {
+    let side = width.max(height).max(1);
+    u32::BITS - (side - 1).leading_zeros()
+}
+
+/// Lock held while loading the source of an image, which is forgotten once
+/// nobody holds or waits for it.
+struct LoadingLock<'a> {
+    loading: &'a Mutex<HashMap<Url, Arc<AsyncMutex<()>>>>,
+    target: &'a Url,
+    lock: Arc<AsyncMutex<()>>,
+}
+
+impl<'a> LoadingLock<'a> {
+    fn new(dzi: &'a Dzi, target: &'a Url) -> Self {
+        let lock = Arc::clone(
+            dzi.loading
+                .lock()
+                .expect("loading lock must not be poisoned")
+                .entry(target.clone())
+                .or_default(),
+        );
+        Self {
+            loading: &dzi.loading,
+            target,
+            lock,
+        }
+    }
+}
+
+impl Drop for LoadingLock<'_> {
+    fn drop(&mut self) {
+        let mut loading = self
+            .loading
+            .lock()
+            .expect("loading lock must not be poisoned");
+        // The map holds the other reference when nobody else does.
+        if Arc::strong_count(&self.lock) == 2 {
+            loading.remove(self.target);
+        }
+    }
+}
+
+/// Cache of the levels of images, evicting the levels of the least recently
+/// used images once the capacity is exceeded.
+///
+/// Levels of an image are evicted from the lowest, so that its full
+/// resolution level, from which the others are downscaled, is evicted last.
+/// Images expire along with all of their levels once they were cached for the
+/// TTL.
+struct LevelCache {
+    capacity: u64,
+    ttl: Duration,
+    size: u64,
+    clock: u64,
+    images: HashMap<Url, CachedImage>,
+}
+
+struct CachedImage {
+    dimensions: (u32, u32),
+    levels: BTreeMap<u32, Arc<DynamicImage>>,
+    last_used: u64,
+    cached_at: Instant,
+}
+
+impl LevelCache {
+    fn new(capacity: u64, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            size: 0,
+            clock: 0,
+            images: HashMap::new(),
+        }
+    }
+
+    /// Returns the lowest cached level of an image which is at least `level`.
+    fn get(&mut self, target: &Url, level: u32) -> Option<(u32, Arc<DynamicImage>)> {
+        self.clock += 1;
+        let clock = self.clock;
+        let cached = self.fresh(target)?;
+        let (&level, image) = cached.levels.range(level..).next()?;
+        cached.last_used = clock;
+        Some((level, Arc::clone(image)))
+    }
+
+    fn dimensions(&mut self, target: &Url) -> Option<(u32, u32)> {
+        self.fresh(target).map(|cached| cached.dimensions)
+    }
+
+    /// Returns an image unless it's expired, in which case it's removed.
+    fn fresh(&mut self, target: &Url) -> Option<&mut CachedImage> {
+        let ttl = self.ttl;
+        if self
+            .images
+            .get(target)
+            .is_some_and(|cached| cached.cached_at.elapsed() >= ttl)
+        {
+            self.remove(target);
+        }
+        self.images.get_mut(target)
+    }
+
+    /// Removes an image along with all of its levels.
+    fn remove(&mut self, target: &Url) {
+        if let Some(removed) = self.images.remove(target) {
+            self.size -= removed
+                .levels
+                .values()
+                .map(|image| image_size(image))
+                .sum::<u64>();
+        }
+    }
+
+    /// Caches the full resolution level of an image, replacing any of its
+    /// levels which are cached.
+    fn insert_source(&mut self, target: &Url, level: u32, image: Arc<DynamicImage>) {
+        self.remove(target);
+
+        if image_size(&image) > self.capacity {
+            tracing::warn!(
+                %target,
+                capacity = self.capacity,
+                "Deep Zoom source exceeds the cache capacity, so is decoded for every tile"
+            );
+            return;
+        }
+
+        self.images.insert(
+            target.clone(),
+            CachedImage {
+                dimensions: (image.width(), image.height()),
+                levels: BTreeMap::new(),
+                last_used: 0,
+                cached_at: Instant::now(),
+            },
+        );
+        self.insert(target, level, image);
+    }
+
+    /// Caches a level of an image whose source is cached.
+    fn insert(&mut self, target: &Url, level: u32, image: Arc<DynamicImage>) {
+        let size = image_size(&image);
+        if size > self.capacity {
+            return;
+        }
+
+        self.clock += 1;
+        let clock = self.clock;
+        let Some(cached) = self.fresh(target) else {
+            return;
+        };
+        cached.last_used = clock;
+        if let Some(replaced) = cached.levels.insert(level, image) {
+            self.size -= image_size(&replaced);
+        }
+        self.size += size;
+
+        while self.size > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Evicts the lowest level of the least recently used image.
+    fn evict(&mut self) {
+        let Some((target, cached)) = self
+            .images
+            .iter_mut()
+            .min_by_key(|(_, cached)| cached.last_used)
+        else {
+            return;
+        };
+
+        if let Some((_, evicted)) = cached.levels.pop_first() {
+            self.size -= image_size(&evicted);
+        }
+        if cached.levels.is_empty() {
+            let target = target.clone();
+            self.images.remove(&target);
+        }
+    }
+}
+
+fn image_size(image: &DynamicImage) -> u64 {
+    image.as_bytes().len() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use image::GrayImage;
+
+    use super::*;
+
+    fn dzi() -> Dzi {
+        Dzi::new("https://example.com/images".parse().unwrap())
+    }
+
+    fn target() -> Url {
+        "https://example.com/images/a.png".parse().unwrap()
+    }
+
+    fn image(width: u32, height: u32) -> Arc<DynamicImage> {
+        Arc::new(DynamicImage::ImageLuma8(GrayImage::new(width, height)))
+    }
+
+    #[test]
+    fn computes_max_levels() {
+        assert_eq!(max_level((1, 1)), 0);
+        assert_eq!(max_level((2, 1)), 1);
+        assert_eq!(max_level((3, 3)), 2);
+        assert_eq!(max_level((100, 256)), 8);
+        assert_eq!(max_level((257, 1)), 9);
+    }
+
+    #[test]
+    fn resolves_requests() {
+        assert!(matches!(
+            dzi().resolve("/a.png.dzi"),
+            Ok(DziRequest::Descriptor { target: t }) if t == target()
+        ));
+        assert!(matches!(
+            dzi().resolve("/a.png_files/3/1_2.jpeg"),
+            Ok(DziRequest::Tile { target: t, level: 3, column: 1, row: 2 }) if t == target()
+        ));
+
+        for path in [
+            "/a.png_files/3/1_2.png",
+            "/a.png_files/32/0_0.jpeg",
+            "/a.png_files/3/1.jpeg",
+            "/../a.png.dzi",
+            "/.dzi",
+        ] {
+            assert!(dzi().resolve(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn cuts_tiles_with_overlap() {
+        let dzi = dzi().set_tile_size(4).set_overlap(1);
+        dzi.insert_source(&target(), DynamicImage::ImageLuma8(GrayImage::new(10, 6)));
+        let tile = |level, column, row| {
+            let source = dzi.cached_level(&target(), level).unwrap();
+            dzi.tile(&target(), source, level, column, row)
+                .map(|tile| (tile.width(), tile.height()))
+        };
+
+        assert_eq!(tile(4, 0, 0), Some((5, 5)));
+        assert_eq!(tile(4, 1, 0), Some((6, 5)));
+        assert_eq!(tile(4, 2, 1), Some((3, 3)));
+        assert_eq!(tile(4, 3, 0), None);
+        assert_eq!(tile(4, 0, 2), None);
+
+        // Level 3 is downscaled to 5x3 and then cached.
+        assert_eq!(tile(3, 1, 0), Some((2, 3)));
+        assert_eq!(dzi.cached_level(&target(), 3).unwrap().0, 3);
+
+        let source = dzi.cached_level(&target(), 4).unwrap();
+        assert!(dzi.tile(&target(), source, 5, 0, 0).is_none());
+    }
+
+    #[test]
+    fn evicts_lower_levels_of_least_recently_used_images() {
+        let other: Url = "https://example.com/images/b.png".parse().unwrap();
+        let mut cache = LevelCache::new(100, DEFAULT_CACHE_TTL);
+        cache.insert_source(&target(), 3, image(8, 8));
+        cache.insert(&target(), 2, image(4, 4));
+        cache.insert(&target(), 1, image(2, 2));
+        assert_eq!(cache.size, 84);
+
+        cache.insert_source(&other, 3, image(6, 6));
+        assert_eq!(cache.size, 100);
+        assert_eq!(cache.get(&target(), 1).map(|(level, _)| level), Some(3));
+        assert_eq!(cache.get(&other, 0).map(|(level, _)| level), Some(3));
+
+        // The full resolution level is evicted last, along with the image.
+        cache.insert(&other, 2, image(6, 6));
+        assert_eq!(cache.dimensions(&target()), None);
+        assert_eq!(cache.dimensions(&other), Some((6, 6)));
+        assert_eq!(cache.size, 72);
+    }
+
+    #[test]
+    fn skips_levels_exceeding_the_capacity() {
+        let mut cache = LevelCache::new(100, DEFAULT_CACHE_TTL);
+        cache.insert_source(&target(), 4, image(16, 16));
+        assert_eq!(cache.dimensions(&target()), None);
+
+        // Levels are only cached along with their source.
+        cache.insert(&target(), 3, image(8, 8));
+        assert!(cache.get(&target(), 3).is_none());
+        assert_eq!(cache.size, 0);
+    }
+
+    #[test]
+    fn expires_images() {
+        let mut cache = LevelCache::new(100, Duration::ZERO);
+        cache.insert_source(&target(), 3, image(8, 8));
+        assert!(cache.get(&target(), 3).is_none());
+        assert_eq!(cache.dimensions(&target()), None);
+        assert_eq!(cache.size, 0);
+
+        // Levels of expired images aren't cached either.
+        cache.ttl = DEFAULT_CACHE_TTL;
+        cache.insert(&target(), 2, image(4, 4));
+        assert_eq!(cache.size, 0);
+        cache.insert_source(&target(), 3, image(8, 8));
+        assert_eq!(cache.dimensions(&target()), Some((8, 8)));
+    }
+
+    #[test]
+    fn keeps_cache_settings() {
+        let cache = |dzi: &Dzi| {
+            let cache = dzi.cache.lock().unwrap();
+            (cache.capacity, cache.ttl)
+        };
+        let ttl = Duration::from_secs(60);
+
+        assert_eq!(
+            cache(&dzi().set_cache_capacity(100).set_cache_ttl(ttl)),
+            (100, ttl)
+        );
+        assert_eq!(
+            cache(&dzi().set_cache_ttl(ttl).set_cache_capacity(100)),
+            (100, ttl)
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_sources_once() {
+        let (dzi, target) = (dzi(), target());
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            Ok::<_, ()>(dzi.insert_source(&target, (*image(4, 4)).clone()))
+        };
+
+        let (a, b) = tokio::join!(
+            dzi.level_or_load(&target, 0, load),
+            dzi.level_or_load(&target, 1, load),
+        );
+        assert_eq!(a.unwrap().0, 2);
+        assert_eq!(b.unwrap().0, 2);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(dzi.loading.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,759 @@
+//! Encoding of transformed images into their output format.
+use std::{
+    borrow::Cow,
+    io::{BufWriter, Cursor, Seek, Write},
+    time::{Duration, Instant},
+};
+
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageFormat,
+};
+
+use crate::{
+    metadata::Metadata,
+    quantize::{quantize, IndexedImage, PaletteOptions},
+    service::ImageXformError,
+};
+
+/// Default JPEG quality, matching that of the `image` crate.
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// Default AVIF quality, matching that of the `image` crate.
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+
+/// Default AVIF speed, matching that of the `image` crate.
+const DEFAULT_AVIF_SPEED: u8 = 4;
+
+/// Lowest quality considered when searching for an encoding within a byte
+/// budget.
+const MIN_QUALITY: u8 = 1;
+
+/// Smallest factor by which dimensions are reduced in a single step when
+/// searching for an encoding within a byte budget.
+const MIN_DOWNSCALE_STEP: f64 = 0.5;
+
+/// Largest factor by which dimensions are reduced in a single step when
+/// searching for an encoding within a byte budget.
+const MAX_DOWNSCALE_STEP: f64 = 0.9;
+
+/// Options which influence how an image is encoded.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct EncodeOptions {
+    /// Emit progressive JPEGs and Adam7-interlaced PNGs.
+    pub(crate) progressive: bool,
+
+    /// Reduce PNGs to an indexed palette.
+    pub(crate) palette: Option<PaletteOptions>,
+
+    /// Quality of lossy encoders, from 1 to 100.
+    pub(crate) quality: Option<u8>,
+
+    /// Upper bound on the size of the encoded image.
+    pub(crate) byte_budget: Option<ByteBudget>,
+}
+
+/// Upper bound on the size of an encoded image, along with the limits of the
+/// search for an encoding which satisfies it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ByteBudget {
+    /// Maximum size of the encoded image, in bytes.
+    pub(crate) max_bytes: u64,
+
+    /// Whether dimensions may be reduced once quality alone does not suffice.
+    pub(crate) allow_downscale: bool,
+
+    /// Time after which the search is abandoned.
+    ///
+    /// This is checked between encoding attempts, so a single slow attempt may
+    /// overrun it.
+    pub(crate) time_limit: Duration,
+}
+
+/// Encodes `image` as `format` into a buffer, honouring the byte budget of
+/// `options` if there is one.
+pub(crate) fn encode_to_vec(
+    image: &DynamicImage,
+    format: ImageFormat,
+    metadata: &Metadata,
+    options: EncodeOptions,
+) -> Result<Vec<u8>, ImageXformError> {
+    match options.byte_budget {
+        Some(budget) => encode_within_budget(image, format, metadata, options, budget),
+        None => encode_once(image, format, metadata, options),
+    }
+}
+
+fn encode_once(
+    image: &DynamicImage,
+    format: ImageFormat,
+    metadata: &Metadata,
+    options: EncodeOptions,
+) -> Result<Vec<u8>, ImageXformError> {
+    let mut writer = BufWriter::new(Cursor::new(Vec::with_capacity(image.as_bytes().len())));
+    encode_image(image, &mut writer, format, metadata, options)?;
+
+    Ok(writer
+        .into_inner()
+        .map_err(ImageXformError::WriterFinalization)?
+        .into_inner())
+}
+
+/// Searches downward over quality and, if allowed, dimensions until the
+/// encoded image fits within `budget`.
+fn encode_within_budget(
+    image: &DynamicImage,
+    format: ImageFormat,
+    metadata: &Metadata,
+    options: EncodeOptions,
+    budget: ByteBudget,
+) -> Result<Vec<u8>, ImageXformError> {
+    let deadline = Instant::now() + budget.time_limit;
+    let fits = |bytes: &[u8]| bytes.len() as u64 <= budget.max_bytes;
+
+    let mut image = Cow::Borrowed(image);
+    let mut smallest: Option<u64> = None;
+
+    loop {
+        let encoded = encode_once(&image, format, metadata, options)?;
+        if fits(&encoded) {
+            return Ok(encoded);
+        }
+
+        let mut size = encoded.len() as u64;
+
+        if let Some(initial_quality) = quality_of(format, options) {
+            // Binary search for the highest quality which fits.
+            let (mut low, mut high) = (MIN_QUALITY, initial_quality.saturating_sub(1));
+            let mut best = None;
+
+            while low <= high && Instant::now() < deadline {
+                let quality = low + (high - low) / 2;
+                let options = EncodeOptions {
+                    quality: Some(quality),
+                    ..options
+                };
+                let encoded = encode_once(&image, format, metadata, options)?;
+                size = size.min(encoded.len() as u64);
+
+                if fits(&encoded) {
+                    best = Some(encoded);
+                    low = quality + 1;
+                } else if quality == MIN_QUALITY {
+                    break;
+                } else {
+                    high = quality - 1;
+                }
+            }
+
+            if let Some(best) = best {
+                return Ok(best);
+            }
+        }
+
+        smallest = Some(smallest.map_or(size, |smallest| smallest.min(size)));
+
+        if !budget.allow_downscale || Instant::now() >= deadline {
+            break;
+        }
+
+        // Encoded size scales roughly with the pixel count, so each dimension is
+        // scaled by the square root of the overshoot.
+        let step = (budget.max_bytes as f64 / size as f64)
+            .sqrt()
+            .clamp(MIN_DOWNSCALE_STEP, MAX_DOWNSCALE_STEP);
+        let width = (f64::from(image.width()) * step).round() as u32;
+        let height = (f64::from(image.height()) * step).round() as u32;
+
+        // Tiny images may round back to their own dimensions.
+        if width == 0 || height == 0 || (width, height) == (image.width(), image.height()) {
+            break;
+        }
+
+        image = Cow::Owned(image.resize_exact(width, height, FilterType::Lanczos3));
+    }
+
+    Err(ImageXformError::ByteBudgetExceeded {
+        max_bytes: budget.max_bytes,
+        smallest: smallest.unwrap_or_default(),
+    })
+}
+
+/// Returns whether `format` is encoded lossily.
+///
+/// WebP is always encoded losslessly, as the `image` crate has no lossy WebP
+/// encoder.
+pub(crate) fn is_lossy(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Avif)
+}
+
+/// Returns whether `format` is able to represent transparency.
+pub(crate) fn supports_alpha(format: ImageFormat) -> bool {
+    !matches!(format, ImageFormat::Jpeg)
+}
+
+/// Returns the quality an image would be encoded at, for formats whose
+/// encoders are lossy.
+fn quality_of(format: ImageFormat, options: EncodeOptions) -> Option<u8> {
+    match format {
+        ImageFormat::Jpeg => Some(options.quality.unwrap_or(DEFAULT_JPEG_QUALITY)),
+        ImageFormat::Avif => Some(options.quality.unwrap_or(DEFAULT_AVIF_QUALITY)),
+        _ => None,
+    }
+}
+
+/// Encodes `image` as `format`, attaching the given metadata where the format
+/// allows for it.
+fn encode_image<W>(
+    image: &DynamicImage,
+    writer: &mut W,
+    format: ImageFormat,
+    metadata: &Metadata,
+    options: EncodeOptions,
+) -> Result<(), ImageXformError>
+where
+    W: Write + Seek,
+{
+    match format {
+        ImageFormat::Png => match options.palette {
+            Some(palette) => encode_indexed_png(
+                &quantize(image, palette),
+                writer,
+                metadata,
+                options.progressive,
+            )?,
+
+            None if options.progressive => encode_interlaced_png(image, writer, metadata)?,
+
+            None => {
+                let mut encoder = PngEncoder::new(writer);
+                metadata.apply(&mut encoder);
+                image.write_with_encoder(encoder)?;
+            }
+        },
+
+        ImageFormat::Jpeg if options.progressive => {
+            encode_progressive_jpeg(image, writer, metadata, options)?
+        }
+
+        ImageFormat::Jpeg => {
+            let quality = options.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+            let mut encoder = JpegEncoder::new_with_quality(writer, quality);
+            metadata.apply(&mut encoder);
+            image.write_with_encoder(encoder)?;
+        }
+
+        ImageFormat::WebP => {
+            let mut encoder = WebPEncoder::new_lossless(writer);
+            metadata.apply(&mut encoder);
+            image.write_with_encoder(encoder)?;
+        }
+
+        ImageFormat::Avif => {
+            let quality = options.quality.unwrap_or(DEFAULT_AVIF_QUALITY);
+            let mut encoder =
+                AvifEncoder::new_with_speed_quality(writer, DEFAULT_AVIF_SPEED, quality);
+            metadata.apply(&mut encoder);
+            image.write_with_encoder(encoder)?;
+        }
+
+        // Formats without a dedicated encoder never carry metadata.
+        _ => image.write_to(writer, format)?,
+    }
+
+    Ok(())
+}
+
+fn encode_progressive_jpeg<W: Write>(
+    image: &DynamicImage,
+    writer: &mut W,
+    metadata: &Metadata,
+    options: EncodeOptions,
+) -> Result<(), ImageXformError> {
+    let (Ok(width), Ok(height)) = (u16::try_from(image.width()), u16::try_from(image.height()))
+    else {
+        return Err(ImageXformError::Image(image::ImageError::Limits(
+            image::error::LimitError::from_kind(image::error::LimitErrorKind::DimensionError),
+        )));
+    };
+
+    let quality = options.quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    let mut encoder = jpeg_encoder::Encoder::new(writer, quality);
+    encoder.set_progressive(true);
+
+    if let Some(icc_profile) = &metadata.icc_profile {
+        encoder.add_icc_profile(icc_profile)?;
+    }
+
+    if let Some(exif) = &metadata.exif {
+        encoder.add_exif_metadata(exif)?;
+    }
+
+    if image.color().has_color() {
+        encoder.encode(
+            &image.to_rgb8(),
+            width,
+            height,
+            jpeg_encoder::ColorType::Rgb,
+        )?;
+    } else {
+        encoder.encode(
+            &image.to_luma8(),
+            width,
+            height,
+            jpeg_encoder::ColorType::Luma,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Maximum length of the `IDAT` chunks of interlaced PNGs, as PNG chunk lengths
+/// are limited to 2^31 - 1 bytes.
+#[cfg(not(test))]
+const IDAT_CHUNK_LENGTH: usize = (1 << 31) - 1;
+
+/// Tiny chunks, so that tests exercise splitting the image data.
+#[cfg(test)]
+const IDAT_CHUNK_LENGTH: usize = 64;
+
+/// Starting offsets and strides of the seven Adam7 passes, as `(x, y, dx,
+/// dy)`.
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+fn encode_interlaced_png<W: Write>(
+    image: &DynamicImage,
+    writer: &mut W,
+    metadata: &Metadata,
+) -> Result<(), ImageXformError> {
+    let (color_type, bit_depth, samples) = png_samples(image);
+
+    let mut info = png_info(image.width(), image.height(), metadata);
+    info.color_type = color_type;
+    info.bit_depth = bit_depth;
+    info.interlaced = true;
+
+    let bytes_per_pixel = color_type.samples() * (bit_depth as usize).div_ceil(8);
+    write_png(writer, info, &samples, bytes_per_pixel)
+}
+
+fn encode_indexed_png<W: Write>(
+    image: &IndexedImage,
+    writer: &mut W,
+    metadata: &Metadata,
+    interlaced: bool,
+) -> Result<(), ImageXformError> {
+    // Sub-byte depths are only used for non-interlaced output, where scanlines
+    // are trivially packed.
+    let bit_depth = if interlaced { 8 } else { image.bit_depth() };
+
+    let mut info = png_info(image.width, image.height, metadata);
+    info.color_type = png::ColorType::Indexed;
+    info.bit_depth = png::BitDepth::from_u8(bit_depth).expect("Must be a valid bit depth");
+    info.interlaced = interlaced;
+    info.palette = Some(
+        image
+            .palette
+            .iter()
+            .flat_map(|[r, g, b, _]| [*r, *g, *b])
+            .collect::<Vec<_>>()
+            .into(),
+    );
+
+    // Trailing opaque entries may be omitted from the transparency chunk.
+    let mut trns: Vec<u8> = image.palette.iter().map(|[.., a]| *a).collect();
+    while trns.last() == Some(&u8::MAX) {
+        trns.pop();
+    }
+    if !trns.is_empty() {
+        info.trns = Some(trns.into());
+    }
+
+    let samples = if interlaced {
+        image.indices.clone()
+    } else {
+        pack_indices(&image.indices, image.width as usize, bit_depth)
+    };
+
+    write_png(writer, info, &samples, 1)
+}
+
+fn png_info(width: u32, height: u32, metadata: &Metadata) -> png::Info<'_> {
+    let mut info = png::Info::with_size(width, height);
+    info.icc_profile = metadata.icc_profile.as_deref().map(Cow::Borrowed);
+    info.exif_metadata = metadata.exif.as_deref().map(Cow::Borrowed);
+    info
+}
+
+/// Writes a PNG described by `info`.
+///
+/// For interlaced images, `samples` holds whole bytes per pixel; otherwise it
+/// holds packed scanlines.
+fn write_png<W: Write>(
+    writer: &mut W,
+    info: png::Info<'_>,
+    samples: &[u8],
+    bytes_per_pixel: usize,
+) -> Result<(), ImageXformError> {
+    let (width, height, interlaced) = (info.width, info.height, info.interlaced);
+    let mut encoder = png::Encoder::with_info(writer, info)?;
+
+    if interlaced {
+        // Image data is written as a raw chunk, which sequence validation does
+        // not account for.
+        encoder.validate_sequence(false);
+
+        let mut writer = encoder.write_header()?;
+        let data = adam7_filtered(samples, width as usize, height as usize, bytes_per_pixel);
+        let compressed = fdeflate::compress_to_vec(&data);
+        for chunk in compressed.chunks(IDAT_CHUNK_LENGTH) {
+            writer.write_chunk(png::chunk::IDAT, chunk)?;
+        }
+        writer.finish()?;
+    } else {
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(samples)?;
+        writer.finish()?;
+    }
+
+    Ok(())
+}
+
+/// Packs one palette index per byte into scanlines of the given bit depth.
+fn pack_indices(indices: &[u8], width: usize, bit_depth: u8) -> Vec<u8> {
+    if bit_depth == 8 {
+        return indices.to_vec();
+    }
+
+    let per_byte = 8 / usize::from(bit_depth);
+    indices
+        .chunks(width)
+        .flat_map(|row| {
+            row.chunks(per_byte).map(|pixels| {
+                pixels.iter().enumerate().fold(0u8, |byte, (i, &index)| {
+                    byte | index << (8 - usize::from(bit_depth) * (i + 1))
+                })
+            })
+        })
+        .collect()
+}
+
+/// Returns the PNG colour type, bit depth and big-endian samples of `image`.
+fn png_samples(image: &DynamicImage) -> (png::ColorType, png::BitDepth, Vec<u8>) {
+    use png::{BitDepth, ColorType};
+
+    fn big_endian(samples: &[u16]) -> Vec<u8> {
+        samples
+            .iter()
+            .flat_map(|sample| sample.to_be_bytes())
+            .collect()
+    }
+
+    match image {
+        DynamicImage::ImageLuma8(buf) => (ColorType::Grayscale, BitDepth::Eight, buf.to_vec()),
+        DynamicImage::ImageLumaA8(buf) => {
+            (ColorType::GrayscaleAlpha, BitDepth::Eight, buf.to_vec())
+        }
+        DynamicImage::ImageRgb8(buf) => (ColorType::Rgb, BitDepth::Eight, buf.to_vec()),
+        DynamicImage::ImageLuma16(buf) => {
+            (ColorType::Grayscale, BitDepth::Sixteen, big_endian(buf))
+        }
+        DynamicImage::ImageLumaA16(buf) => (
+            ColorType::GrayscaleAlpha,
+            BitDepth::Sixteen,
+            big_endian(buf),
+        ),
+        DynamicImage::ImageRgb16(buf) => (ColorType::Rgb, BitDepth::Sixteen, big_endian(buf)),
+        DynamicImage::ImageRgba16(buf) => (ColorType::Rgba, BitDepth::Sixteen, big_endian(buf)),
+        _ => (
+            ColorType::Rgba,
+            BitDepth::Eight,
+            image.to_rgba8().into_raw(),
+        ),
+    }
+}
+
+/// Splits `samples` into the seven Adam7 passes and filters each scanline, as
+/// expected by the `IDAT` chunk before compression.
+fn adam7_filtered(samples: &[u8], width: usize, height: usize, bytes_per_pixel: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(samples.len() + height * 2);
+
+    for (x0, y0, dx, dy) in ADAM7_PASSES {
+        if x0 >= width || y0 >= height {
+            continue;
+        }
+
+        let row_len = (width - x0).div_ceil(dx) * bytes_per_pixel;
+        let mut previous = vec![0; row_len];
+        let mut current = Vec::with_capacity(row_len);
+
+        for y in (y0..height).step_by(dy) {
+            current.clear();
+            for x in (x0..width).step_by(dx) {
+                let offset = (y * width + x) * bytes_per_pixel;
+                current.extend_from_slice(&samples[offset..offset + bytes_per_pixel]);
+            }
+
+            filter_scanline(&current, &previous, bytes_per_pixel, &mut data);
+            std::mem::swap(&mut previous, &mut current);
+        }
+    }
+
+    data
+}
+
+/// Appends the filter type and filtered bytes of `row` to `out`, choosing the
+/// filter with the smallest sum of absolute values.
+fn filter_scanline(row: &[u8], previous: &[u8], bytes_per_pixel: usize, out: &mut Vec<u8>) {
+    let filtered = |filter: u8| -> Vec<u8> {
+        (0..row.len())
+            .map(|i| {
+                let a = if i >= bytes_per_pixel {
+                    row[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let b = previous[i];
+                let c = if i >= bytes_per_pixel {
+                    previous[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+                let predictor = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                row[i].wrapping_sub(predictor)
+            })
+            .collect()
+    };
+
+    let cost = |bytes: &[u8]| -> u64 {
+        bytes
+            .iter()
+            .map(|&byte| u64::from((byte as i8).unsigned_abs()))
+            .sum()
+    };
+
+    let (filter, bytes) = (0..=4)
+        .map(|filter| (filter, filtered(filter)))
+        .min_by_key(|(_, bytes)| cost(bytes))
+        .expect("There are always candidate filters");
+
+    out.push(filter);
+    out.extend_from_slice(&bytes);
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let (pa, pb, pc) = (
+        (p - i16::from(a)).abs(),
+        (p - i16::from(b)).abs(),
+        (p - i16::from(c)).abs(),
+    );
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, ImageBuffer, Luma, Rgb, Rgba};
+
+    use super::*;
+
+    fn interlaced_round_trip(image: &DynamicImage) -> DynamicImage {
+        let options = EncodeOptions {
+            progressive: true,
+            ..Default::default()
+        };
+        let encoded =
+            encode_to_vec(image, ImageFormat::Png, &Metadata::default(), options).unwrap();
+
+        let decoder = png::Decoder::new(Cursor::new(&encoded));
+        assert!(decoder.read_info().unwrap().info().interlaced);
+
+        image::load_from_memory_with_format(&encoded, ImageFormat::Png).unwrap()
+    }
+
+    #[test]
+    fn round_trips_interlaced_8_bit_images() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(13, 7, |x, y| {
+            Rgb([(x * 19) as u8, (y * 37) as u8, (x * y) as u8])
+        }));
+
+        let decoded = interlaced_round_trip(&image);
+
+        assert!(matches!(decoded, DynamicImage::ImageRgb8(_)));
+        assert_eq!(decoded.as_bytes(), image.as_bytes());
+    }
+
+    #[test]
+    fn round_trips_interlaced_16_bit_images() {
+        let image = DynamicImage::ImageRgba16(ImageBuffer::from_fn(9, 10, |x, y| {
+            Rgba([
+                (x * 7001) as u16,
+                (y * 6007) as u16,
+                513,
+                (x + y) as u16 * 3001,
+            ])
+        }));
+
+        let decoded = interlaced_round_trip(&image);
+
+        assert!(matches!(decoded, DynamicImage::ImageRgba16(_)));
+        assert_eq!(decoded.as_bytes(), image.as_bytes());
+    }
+
+    #[test]
+    fn round_trips_interlaced_images_smaller_than_a_pass() {
+        let image = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(1, 1, Luma([42])));
+
+        assert_eq!(interlaced_round_trip(&image).as_bytes(), image.as_bytes());
+    }
+
+    /// Returns a noisy image, which compresses poorly at high qualities.
+    fn noise(width: u32, height: u32) -> DynamicImage {
+        let mut state = 0x2545_f491_u32;
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |_, _| {
+            let mut channel = || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            };
+            Rgb([channel(), channel(), channel()])
+        }))
+    }
+
+    fn budget(max_bytes: u64, allow_downscale: bool) -> Option<ByteBudget> {
+        Some(ByteBudget {
+            max_bytes,
+            allow_downscale,
+            time_limit: Duration::from_secs(10),
+        })
+    }
+
+    fn encode(image: &DynamicImage, format: ImageFormat, options: EncodeOptions) -> Vec<u8> {
+        encode_to_vec(image, format, &Metadata::default(), options).unwrap()
+    }
+
+    #[test]
+    fn lowers_quality_to_fit_the_budget() {
+        let image = noise(64, 64);
+        let unbudgeted = encode(&image, ImageFormat::Jpeg, EncodeOptions::default());
+        let max_bytes = unbudgeted.len() as u64 / 2;
+
+        let encoded = encode(
+            &image,
+            ImageFormat::Jpeg,
+            EncodeOptions {
+                byte_budget: budget(max_bytes, false),
+                ..Default::default()
+            },
+        );
+
+        assert!(encoded.len() as u64 <= max_bytes);
+        assert_eq!(
+            image::load_from_memory(&encoded).unwrap().dimensions(),
+            (64, 64)
+        );
+    }
+
+    #[test]
+    fn searches_below_an_explicit_quality() {
+        let image = noise(64, 64);
+        let at_quality = |quality| {
+            encode(
+                &image,
+                ImageFormat::Jpeg,
+                EncodeOptions {
+                    quality: Some(quality),
+                    ..Default::default()
+                },
+            )
+        };
+        let within = |quality, max_bytes| {
+            encode(
+                &image,
+                ImageFormat::Jpeg,
+                EncodeOptions {
+                    quality: Some(quality),
+                    byte_budget: budget(max_bytes, false),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // The explicit quality is used as-is when it fits, even if a higher one
+        // would fit too.
+        let q30 = at_quality(30);
+        assert_eq!(within(30, q30.len() as u64 * 2), q30);
+
+        // Otherwise, the highest quality below it which fits is used.
+        let q10 = at_quality(10);
+        let encoded = within(30, q10.len() as u64);
+        assert!(encoded.len() <= q10.len());
+        assert!(encoded.len() < q30.len());
+    }
+
+    #[test]
+    fn downscales_lossless_formats_when_allowed() {
+        let image = noise(64, 64);
+
+        let encoded = encode(
+            &image,
+            ImageFormat::Png,
+            EncodeOptions {
+                byte_budget: budget(4096, true),
+                ..Default::default()
+            },
+        );
+
+        assert!(encoded.len() <= 4096);
+        let (width, height) = image::load_from_memory(&encoded).unwrap().dimensions();
+        assert!(width < 64 && width == height);
+    }
+
+    #[test]
+    fn rejects_impossible_budgets() {
+        for (format, allow_downscale) in [
+            (ImageFormat::Jpeg, false),
+            (ImageFormat::Png, false),
+            (ImageFormat::Jpeg, true),
+        ] {
+            let options = EncodeOptions {
+                byte_budget: budget(10, allow_downscale),
+                ..Default::default()
+            };
+            let result = encode_to_vec(&noise(64, 64), format, &Metadata::default(), options);
+
+            let Err(ImageXformError::ByteBudgetExceeded {
+                max_bytes,
+                smallest,
+            }) = result
+            else {
+                panic!("expected the budget to be exceeded for {format:?}");
+            };
+            assert_eq!(max_bytes, 10);
+            assert!(smallest > 10);
+        }
+    }
+}
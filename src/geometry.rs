@@ -0,0 +1,147 @@
+//! Cropping, flipping, and rotating of images.
+use std::{fmt, str::FromStr};
+
+use image::DynamicImage;
+
+/// Region of the image to crop to, in pixels.
+///
+/// This is set per URL via the `crop_{x}-{y}-{width}-{height}` parameter. A
+/// region which extends past the image is cropped to the image's extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    /// Left edge of the region.
+    pub x: u32,
+
+    /// Top edge of the region.
+    pub y: u32,
+
+    /// Width of the region.
+    pub width: u32,
+
+    /// Height of the region.
+    pub height: u32,
+}
+
+impl FromStr for Crop {
+    type Err = &'static str;
+
+    fn from_str(crop: &str) -> Result<Self, Self::Err> {
+        let mut values = crop.split('-').map(str::parse::<u32>);
+        let mut next = || {
+            values
+                .next()
+                .and_then(Result::ok)
+                .ok_or("Invalid crop region")
+        };
+        let crop = Self {
+            x: next()?,
+            y: next()?,
+            width: next()?,
+            height: next()?,
+        };
+
+        if values.next().is_some() || crop.width == 0 || crop.height == 0 {
+            return Err("Invalid crop region");
+        }
+
+        Ok(crop)
+    }
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}-{}-{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Direction in which the image is flipped.
+///
+/// This is set per URL via the `flip_{flip}` parameter, where `{flip}` is one
+/// of `h`, `v`, or `hv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flip {
+    /// Mirror the image horizontally.
+    Horizontal,
+
+    /// Mirror the image vertically.
+    Vertical,
+
+    /// Mirror the image both horizontally and vertically.
+    Both,
+}
+
+impl FromStr for Flip {
+    type Err = &'static str;
+
+    fn from_str(flip: &str) -> Result<Self, Self::Err> {
+        match flip {
+            "h" => Ok(Self::Horizontal),
+            "v" => Ok(Self::Vertical),
+            "hv" => Ok(Self::Both),
+            _ => Err("Invalid flip"),
+        }
+    }
+}
+
+impl fmt::Display for Flip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flip = match self {
+            Self::Horizontal => "h",
+            Self::Vertical => "v",
+            Self::Both => "hv",
+        };
+        f.write_str(flip)
+    }
+}
+
+/// Scales dimensions down to at most `max`, keeping their aspect ratio, and
+/// rounds them to whole pixels.
+pub(crate) fn scale_down(
+    width: Option<f64>,
+    height: Option<f64>,
+    max: u32,
+) -> (Option<u32>, Option<u32>) {
+    let largest = width.unwrap_or_default().max(height.unwrap_or_default());
+    let scale = (f64::from(max) / largest).min(1.0);
+    let scaled = |dimension: f64| ((dimension * scale).round() as u32).clamp(1, max);
+
+    (width.map(scaled), height.map(scaled))
+}
+
+/// Returns whether `degrees` is a supported rotation, i.e. a quarter, half, or
+/// three-quarter turn clockwise.
+pub(crate) const fn is_valid_rotation(degrees: u16) -> bool {
+    matches!(degrees, 90 | 180 | 270)
+}
+
+/// Crops `image` to `crop`, or returns `None` when the region lies outside of
+/// the image entirely.
+pub(crate) fn crop(image: DynamicImage, crop: Crop) -> Option<DynamicImage> {
+    if crop.x >= image.width() || crop.y >= image.height() {
+        return None;
+    }
+
+    let width = crop.width.min(image.width() - crop.x);
+    let height = crop.height.min(image.height() - crop.y);
+
+    Some(image.crop_imm(crop.x, crop.y, width, height))
+}
+
+/// Flips `image` in the given direction.
+pub(crate) fn flip(image: DynamicImage, flip: Flip) -> DynamicImage {
+    match flip {
+        Flip::Horizontal => image.fliph(),
+        Flip::Vertical => image.flipv(),
+        Flip::Both => image.rotate180(),
+    }
+}
+
+/// Rotates `image` clockwise by a supported number of degrees.
+pub(crate) fn rotate(image: DynamicImage, degrees: u16) -> DynamicImage {
+    match degrees {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image,
+    }
+}
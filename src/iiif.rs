@@ -0,0 +1,797 @@
+//! The IIIF Image API 3.0, as spoken by viewers such as Mirador.
+use image::ImageFormat;
+use url::Url;
+
+use crate::{
+    geometry::{Crop, Flip},
+    image_type::OutputFormat,
+    source::{self, with_trailing_slash},
+    transformation_params::TransformationParams,
+};
+
+/// JSON-LD context of IIIF Image API 3.0 descriptors.
+const CONTEXT: &str = "http://iiif.io/api/image/3/context.json";
+
+/// Configuration of the IIIF Image API 3.0.
+///
+/// Images are requested as
+/// `{identifier}/{region}/{size}/{rotation}/{quality}.{format}`, and described
+/// by `{identifier}/info.json`, where the identifier is resolved against the
+/// source URL. Identifiers may hold slashes, either encoded as `%2F` or not.
+///
+/// The service complies with level 2, provided JPEG and PNG are among the
+/// supported image types, and additionally supports mirroring, upscaling, and
+/// the `gray` quality. Rotations other than multiples of 90 degrees and the
+/// `bitonal` quality are not implemented.
+///
+/// The size of images is limited via `maxWidth`, `maxHeight`, and `maxArea`,
+/// which are listed in `info.json` and default to 4096 pixels wide and high,
+/// and 4096×4096 pixels in area. The `max` size scales down to within the
+/// limits, whereas larger sizes, including upscaled ones, are rejected.
+///
+/// Sources are fetched anew for every request, and `info.json` merely reads
+/// their dimensions, without decoding them. Since viewers request many tiles
+/// of the same image, a caching layer, such as a CDN or a caching proxy in
+/// front of the source, is required in practice.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::Iiif;
+///
+/// let iiif = Iiif::new(
+///     "https://example.com/iiif/".parse().unwrap(),
+///     "https://archive.example.com/scans/".parse().unwrap(),
+/// )
+/// .set_max_width(4096)
+/// .set_max_area(4096 * 4096);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Iiif {
+    base: Url,
+    source: Url,
+    limits: SizeLimits,
+}
+
+impl Iiif {
+    /// Create a new [`Iiif`], given the URL the service is reachable at and
+    /// the URL identifiers are resolved against.
+    pub fn new(base: Url, source: Url) -> Self {
+        Self {
+            base: with_trailing_slash(base),
+            source: with_trailing_slash(source),
+            limits: SizeLimits::DEFAULT,
+        }
+    }
+
+    /// Set the maximum width of images, in pixels (`maxWidth`). Defaults to
+    /// 4096.
+    ///
+    /// Unless a maximum height is set, this limits the height as well.
+    pub fn set_max_width(self, max_width: u32) -> Self {
+        Self {
+            limits: SizeLimits {
+                max_width: Some(max_width),
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    /// Set the maximum height of images, in pixels (`maxHeight`). Defaults to
+    /// the maximum width.
+    pub fn set_max_height(self, max_height: u32) -> Self {
+        Self {
+            limits: SizeLimits {
+                max_height: Some(max_height),
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    /// Set the maximum area of images, in pixels (`maxArea`). Defaults to
+    /// 4096×4096.
+    pub fn set_max_area(self, max_area: u64) -> Self {
+        Self {
+            limits: SizeLimits {
+                max_area: Some(max_area),
+                ..self.limits
+            },
+            ..self
+        }
+    }
+
+    /// Resolves the path of a request, relative to where the service is
+    /// mounted.
+    pub(crate) fn resolve(&self, path: &str) -> Result<IiifRequest, IiifError> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let segments: Vec<&str> = path.split('/').collect();
+
+        match segments[..] {
+            [ref identifier @ .., "info.json"] if !identifier.is_empty() => {
+                let identifier = identifier.join("/");
+                Ok(IiifRequest::Info {
+                    id: self.id(&identifier),
+                    target: self.target(&identifier)?,
+                })
+            }
+
+            [ref identifier @ .., region, size, rotation, quality_format]
+                if !identifier.is_empty() =>
+            {
+                let (quality, format) = quality_format
+                    .rsplit_once('.')
+                    .ok_or(IiifError::Invalid("quality and format are required"))?;
+
+                Ok(IiifRequest::Image {
+                    target: self.target(&identifier.join("/"))?,
+                    image_request: ImageRequest {
+                        region: region.parse()?,
+                        size: size.parse()?,
+                        rotation: rotation.parse()?,
+                        gray: parse_quality(quality)?,
+                        format: ImageFormat::from_extension(format)
+                            .ok_or(IiifError::Unsupported("format is not supported"))?,
+                        limits: self.limits,
+                    },
+                })
+            }
+
+            _ if !path.is_empty() => Ok(IiifRequest::Redirect {
+                location: format!("{}/info.json", self.id(path.trim_end_matches('/'))),
+            }),
+
+            _ => Err(IiifError::Invalid("identifier is required")),
+        }
+    }
+
+    /// Returns the `info.json` descriptor of an image.
+    pub(crate) fn info(
+        &self,
+        id: &str,
+        (width, height): (u32, u32),
+        formats: impl Iterator<Item = ImageFormat>,
+    ) -> String {
+        let extra_formats: Vec<&str> = formats
+            .filter(|format| !matches!(format, ImageFormat::Jpeg | ImageFormat::Png))
+            .filter_map(|format| format.extensions_str().first().copied())
+            .collect();
+
+        let mut info = serde_json::json!({
+            "@context": CONTEXT,
+            "id": id,
+            "type": "ImageService3",
+            "protocol": "http://iiif.io/api/image",
+            "profile": "level2",
+            "width": width,
+            "height": height,
+            "extraQualities": ["gray"],
+            "extraFormats": extra_formats,
+            "extraFeatures": ["mirroring", "sizeUpscaling"],
+        });
+
+        let limits = [
+            ("maxWidth", self.limits.max_width.map(u64::from)),
+            ("maxHeight", self.limits.max_height.map(u64::from)),
+            ("maxArea", self.limits.max_area),
+        ];
+        for (key, limit) in limits {
+            if let Some(limit) = limit {
+                info[key] = limit.into();
+            }
+        }
+
+        info.to_string()
+    }
+
+    fn id(&self, identifier: &str) -> String {
+        format!("{}{identifier}", self.base)
+    }
+
+    fn target(&self, identifier: &str) -> Result<Url, IiifError> {
+        // Encoded slashes separate path segments of the source, whereas any
+        // other encoded characters remain encoded.
+        source::resolve(
+            &self.source,
+            &identifier.replace("%2F", "/").replace("%2f", "/"),
+        )
+        .ok_or(IiifError::Invalid("identifier is not allowed"))
+    }
+}
+
+/// Reason a IIIF request could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum IiifError {
+    /// The request is malformed.
+    #[error("{0}")]
+    Invalid(&'static str),
+
+    /// The request is valid, but asks for a feature which is not implemented.
+    #[error("{0}")]
+    Unsupported(&'static str),
+}
+
+impl IiifError {
+    pub(crate) const fn status(self) -> http::StatusCode {
+        match self {
+            Self::Invalid(_) => http::StatusCode::BAD_REQUEST,
+            Self::Unsupported(_) => http::StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+}
+
+/// Request of the IIIF Image API.
+#[derive(Debug)]
+pub(crate) enum IiifRequest {
+    /// Request of the `info.json` descriptor of an image.
+    Info { id: String, target: Url },
+
+    /// Request of an image.
+    Image {
+        target: Url,
+        image_request: ImageRequest,
+    },
+
+    /// Request of the base URI of an image, which redirects to its descriptor.
+    Redirect { location: String },
+}
+
+/// Parameters of an image request.
+#[derive(Debug)]
+pub(crate) struct ImageRequest {
+    region: Region,
+    size: Size,
+    rotation: Rotation,
+    gray: bool,
+    format: ImageFormat,
+    limits: SizeLimits,
+}
+
+impl ImageRequest {
+    pub(crate) const fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Returns the transform parameters of the request, given the dimensions
+    /// of the image.
+    pub(crate) fn params(&self, dimensions: (u32, u32)) -> Result<TransformationParams, IiifError> {
+        let crop = self.region.crop(dimensions)?;
+        let region_dimensions = crop.map_or(dimensions, |crop| (crop.width, crop.height));
+        let (width, height) = self.size.dimensions(region_dimensions, self.limits)?;
+        let resized = (width, height) != region_dimensions;
+
+        Ok(TransformationParams {
+            width: resized.then_some(width),
+            height: resized.then_some(height),
+            crop,
+            rotate: (self.rotation.degrees != 0).then_some(self.rotation.degrees),
+            flip: self.rotation.mirror.then_some(Flip::Horizontal),
+            gray: self.gray.then_some(true),
+            format: Some(OutputFormat::Format(self.format)),
+            ..Default::default()
+        })
+    }
+}
+
+/// Region of an image request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Region {
+    Full,
+    Square,
+    Pixels(u32, u32, u32, u32),
+    Percent(f64, f64, f64, f64),
+}
+
+impl Region {
+    /// Returns the region in pixels, cropped to the image's extent, or `None`
+    /// when it spans the whole image.
+    fn crop(self, (image_width, image_height): (u32, u32)) -> Result<Option<Crop>, IiifError> {
+        let (x, y, width, height) = match self {
+            Self::Full => return Ok(None),
+
+            Self::Square => {
+                let side = image_width.min(image_height);
+                (
+                    (image_width - side) / 2,
+                    (image_height - side) / 2,
+                    side,
+                    side,
+                )
+            }
+
+            Self::Pixels(x, y, width, height) => (x, y, width, height),
+
+            Self::Percent(x, y, width, height) => {
+                let scale = |percent: f64, dimension: u32| {
+                    (percent / 100.0 * f64::from(dimension)).round() as u32
+                };
+                (
+                    scale(x, image_width),
+                    scale(y, image_height),
+                    scale(width, image_width),
+                    scale(height, image_height),
+                )
+            }
+        };
+
+        if x >= image_width || y >= image_height || width == 0 || height == 0 {
+            return Err(IiifError::Invalid("region is outside of the image"));
+        }
+
+        let crop = Crop {
+            x,
+            y,
+            width: width.min(image_width - x),
+            height: height.min(image_height - y),
+        };
+
+        let full = (0, 0, image_width, image_height);
+        Ok(((crop.x, crop.y, crop.width, crop.height) != full).then_some(crop))
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = IiifError;
+
+    fn from_str(region: &str) -> Result<Self, Self::Err> {
+        const INVALID: IiifError = IiifError::Invalid("region is invalid");
+
+        match region {
+            "full" => Ok(Self::Full),
+            "square" => Ok(Self::Square),
+            _ => match region.strip_prefix("pct:") {
+                Some(percent) => {
+                    let [x, y, width, height] = parse_list::<f64, 4>(percent).ok_or(INVALID)?;
+                    if [x, y, width, height]
+                        .iter()
+                        .any(|value| !value.is_finite() || *value < 0.0)
+                    {
+                        return Err(INVALID);
+                    }
+                    Ok(Self::Percent(x, y, width, height))
+                }
+
+                None => {
+                    let [x, y, width, height] = parse_list::<u32, 4>(region).ok_or(INVALID)?;
+                    Ok(Self::Pixels(x, y, width, height))
+                }
+            },
+        }
+    }
+}
+
+/// Size of an image request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Size {
+    /// Whether the image may be scaled beyond the region, as marked by `^`.
+    upscale: bool,
+    kind: SizeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SizeKind {
+    /// `max`
+    Max,
+
+    /// `w,`
+    Width(u32),
+
+    /// `,h`
+    Height(u32),
+
+    /// `pct:n`
+    Percent(f64),
+
+    /// `w,h`
+    Exact(u32, u32),
+
+    /// `!w,h`
+    Confined(u32, u32),
+}
+
+impl Size {
+    /// Returns the dimensions the region is scaled to.
+    fn dimensions(
+        self,
+        (width, height): (u32, u32),
+        limits: SizeLimits,
+    ) -> Result<(u32, u32), IiifError> {
+        let scaled = |dimension: u32, scale: f64| (f64::from(dimension) * scale).round() as u32;
+
+        let dimensions = match self.kind {
+            SizeKind::Max => limits.fit((width, height), self.upscale),
+            SizeKind::Width(w) => (w, scaled(height, f64::from(w) / f64::from(width)).max(1)),
+            SizeKind::Height(h) => (scaled(width, f64::from(h) / f64::from(height)).max(1), h),
+            SizeKind::Percent(percent) => (
+                scaled(width, percent / 100.0),
+                scaled(height, percent / 100.0),
+            ),
+            SizeKind::Exact(w, h) => (w, h),
+            SizeKind::Confined(w, h) => {
+                let scale = (f64::from(w) / f64::from(width)).min(f64::from(h) / f64::from(height));
+                (
+                    scaled(width, scale).clamp(1, w),
+                    scaled(height, scale).clamp(1, h),
+                )
+            }
+        };
+
+        if dimensions.0 == 0 || dimensions.1 == 0 {
+            return Err(IiifError::Invalid("size is empty"));
+        }
+
+        if !self.upscale && (dimensions.0 > width || dimensions.1 > height) {
+            return Err(IiifError::Invalid(
+                "size exceeds the region, but upscaling was not requested",
+            ));
+        }
+
+        if !limits.allows(dimensions) {
+            return Err(IiifError::Invalid("size exceeds the maximum size"));
+        }
+
+        Ok(dimensions)
+    }
+}
+
+/// Limits of the size of images, as listed in `info.json`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct SizeLimits {
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    max_area: Option<u64>,
+}
+
+impl SizeLimits {
+    /// Limits of images unless configured otherwise.
+    const DEFAULT: Self = Self {
+        max_width: Some(4096),
+        max_height: None,
+        max_area: Some(4096 * 4096),
+    };
+
+    /// Returns the maximum height, which defaults to the maximum width, as in
+    /// `info.json`.
+    fn max_height(self) -> Option<u32> {
+        self.max_height.or(self.max_width)
+    }
+
+    fn allows(self, (width, height): (u32, u32)) -> bool {
+        self.max_width.is_none_or(|max_width| width <= max_width)
+            && self
+                .max_height()
+                .is_none_or(|max_height| height <= max_height)
+            && self
+                .max_area
+                .is_none_or(|max_area| u64::from(width) * u64::from(height) <= max_area)
+    }
+
+    /// Returns the largest dimensions of the given aspect ratio within the
+    /// limits, which are at most `dimensions` unless `upscale` is set.
+    fn fit(self, (width, height): (u32, u32), upscale: bool) -> (u32, u32) {
+        let (w, h) = (f64::from(width), f64::from(height));
+        let scale = [
+            self.max_width.map(|max_width| f64::from(max_width) / w),
+            self.max_height()
+                .map(|max_height| f64::from(max_height) / h),
+            self.max_area
+                .map(|max_area| (max_area as f64 / (w * h)).sqrt()),
+            (!upscale).then_some(1.0),
+        ]
+        .into_iter()
+        .flatten()
+        .reduce(f64::min);
+
+        match scale {
+            Some(scale) if scale != 1.0 => (
+                ((w * scale).floor() as u32).max(1),
+                ((h * scale).floor() as u32).max(1),
+            ),
+            _ => (width, height),
+        }
+    }
+}
+
+impl std::str::FromStr for Size {
+    type Err = IiifError;
+
+    fn from_str(size: &str) -> Result<Self, Self::Err> {
+        const INVALID: IiifError = IiifError::Invalid("size is invalid");
+
+        let (upscale, size) = match size.strip_prefix('^') {
+            Some(size) => (true, size),
+            None => (false, size),
+        };
+
+        let kind = if size == "max" {
+            SizeKind::Max
+        } else if let Some(percent) = size.strip_prefix("pct:") {
+            let percent: f64 = percent.parse().map_err(|_| INVALID)?;
+            if !percent.is_finite() || percent <= 0.0 {
+                return Err(INVALID);
+            }
+            SizeKind::Percent(percent)
+        } else if let Some(confined) = size.strip_prefix('!') {
+            let [width, height] = parse_list::<u32, 2>(confined).ok_or(INVALID)?;
+            SizeKind::Confined(width, height)
+        } else {
+            match size.split_once(',').ok_or(INVALID)? {
+                (width, "") => SizeKind::Width(width.parse().map_err(|_| INVALID)?),
+                ("", height) => SizeKind::Height(height.parse().map_err(|_| INVALID)?),
+                (width, height) => SizeKind::Exact(
+                    width.parse().map_err(|_| INVALID)?,
+                    height.parse().map_err(|_| INVALID)?,
+                ),
+            }
+        };
+
+        Ok(Self { upscale, kind })
+    }
+}
+
+/// Rotation of an image request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rotation {
+    /// Whether the image is mirrored before rotating, as marked by `!`.
+    mirror: bool,
+
+    /// Clockwise rotation, in degrees.
+    degrees: u16,
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = IiifError;
+
+    fn from_str(rotation: &str) -> Result<Self, Self::Err> {
+        let (mirror, rotation) = match rotation.strip_prefix('!') {
+            Some(rotation) => (true, rotation),
+            None => (false, rotation),
+        };
+
+        let degrees: f64 = rotation
+            .parse()
+            .ok()
+            .filter(|degrees: &f64| (0.0..=360.0).contains(degrees))
+            .ok_or(IiifError::Invalid("rotation is invalid"))?;
+
+        if degrees % 90.0 != 0.0 {
+            return Err(IiifError::Unsupported(
+                "rotation by other than multiples of 90 degrees is not supported",
+            ));
+        }
+
+        Ok(Self {
+            mirror,
+            degrees: (degrees as u16) % 360,
+        })
+    }
+}
+
+/// Parses the quality of an image request, returning whether it's gray.
+fn parse_quality(quality: &str) -> Result<bool, IiifError> {
+    match quality {
+        "default" | "color" => Ok(false),
+        "gray" => Ok(true),
+        "bitonal" => Err(IiifError::Unsupported("bitonal quality is not supported")),
+        _ => Err(IiifError::Invalid("quality is invalid")),
+    }
+}
+
+/// Parses exactly `N` comma-separated values.
+fn parse_list<T: std::str::FromStr, const N: usize>(list: &str) -> Option<[T; N]> {
+    let values: Vec<T> = list
+        .split(',')
+        .map(|value| value.parse().ok())
+        .collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iiif() -> Iiif {
+        Iiif::new(
+            "https://example.com/iiif".parse().unwrap(),
+            "https://archive.example.com/scans".parse().unwrap(),
+        )
+    }
+
+    fn size(size: &str, region: (u32, u32)) -> Result<(u32, u32), IiifError> {
+        size.parse::<Size>()?
+            .dimensions(region, SizeLimits::default())
+    }
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!("full".parse(), Ok(Region::Full));
+        assert_eq!("square".parse(), Ok(Region::Square));
+        assert_eq!("1,2,3,4".parse(), Ok(Region::Pixels(1, 2, 3, 4)));
+        assert_eq!(
+            "pct:10,20,30.5,40".parse(),
+            Ok(Region::Percent(10.0, 20.0, 30.5, 40.0))
+        );
+
+        for region in [
+            "",
+            "1,2,3",
+            "1,2,3,4,5",
+            "-1,2,3,4",
+            "pct:-1,0,1,1",
+            "pct:nan,0,1,1",
+        ] {
+            assert!(region.parse::<Region>().is_err(), "{region}");
+        }
+    }
+
+    #[test]
+    fn crops_regions() {
+        assert_eq!(Region::Full.crop((40, 30)), Ok(None));
+        assert_eq!(
+            Region::Square.crop((40, 30)),
+            Ok(Some(Crop {
+                x: 5,
+                y: 0,
+                width: 30,
+                height: 30
+            }))
+        );
+        assert_eq!(
+            Region::Pixels(30, 20, 100, 100).crop((40, 30)),
+            Ok(Some(Crop {
+                x: 30,
+                y: 20,
+                width: 10,
+                height: 10
+            }))
+        );
+        assert_eq!(
+            Region::Percent(50.0, 0.0, 50.0, 100.0).crop((40, 30)),
+            Ok(Some(Crop {
+                x: 20,
+                y: 0,
+                width: 20,
+                height: 30
+            }))
+        );
+        assert_eq!(Region::Pixels(0, 0, 40, 30).crop((40, 30)), Ok(None));
+        assert!(Region::Pixels(40, 0, 1, 1).crop((40, 30)).is_err());
+        assert!(Region::Pixels(0, 0, 0, 1).crop((40, 30)).is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(size("max", (400, 300)), Ok((400, 300)));
+        assert_eq!(size("200,", (400, 300)), Ok((200, 150)));
+        assert_eq!(size(",150", (400, 300)), Ok((200, 150)));
+        assert_eq!(size("pct:25", (400, 300)), Ok((100, 75)));
+        assert_eq!(size("100,100", (400, 300)), Ok((100, 100)));
+        assert_eq!(size("!200,200", (400, 300)), Ok((200, 150)));
+        assert_eq!(size("^800,", (400, 300)), Ok((800, 600)));
+        assert_eq!(size("^pct:200", (400, 300)), Ok((800, 600)));
+
+        assert!(size("800,", (400, 300)).is_err());
+        assert!(size("pct:200", (400, 300)).is_err());
+        assert!(size("0,", (400, 300)).is_err());
+        for size in ["", "full", "200", "pct:0", "pct:-5", "!200,", "a,b", "^"] {
+            assert!(size.parse::<Size>().is_err(), "{size}");
+        }
+    }
+
+    #[test]
+    fn limits_sizes() {
+        let limits = SizeLimits {
+            max_width: Some(300),
+            max_height: None,
+            max_area: Some(300 * 100),
+        };
+        let dimensions = |size: &str| size.parse::<Size>().unwrap().dimensions((400, 200), limits);
+
+        // `max` scales down to within the limits, which `^max` may scale up to.
+        assert_eq!(dimensions("max"), Ok((244, 122)));
+        assert_eq!(
+            "^max"
+                .parse::<Size>()
+                .unwrap()
+                .dimensions((100, 50), limits),
+            Ok((244, 122))
+        );
+        assert_eq!(dimensions("200,"), Ok((200, 100)));
+        assert!(dimensions("400,").is_err());
+        assert!(dimensions("300,150").is_err());
+
+        // The maximum height defaults to the maximum width.
+        let limits = SizeLimits {
+            max_width: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(limits.fit((200, 400), false), (50, 100));
+        assert!(!limits.allows((50, 101)));
+    }
+
+    #[test]
+    fn parses_rotations() {
+        let rotation = |degrees, mirror| Ok(Rotation { mirror, degrees });
+
+        assert_eq!("0".parse(), rotation(0, false));
+        assert_eq!("90".parse(), rotation(90, false));
+        assert_eq!("180.0".parse(), rotation(180, false));
+        assert_eq!("360".parse(), rotation(0, false));
+        assert_eq!("!270".parse(), rotation(270, true));
+
+        assert_eq!(
+            "45".parse::<Rotation>().map_err(|err| err.status()),
+            Err(http::StatusCode::NOT_IMPLEMENTED)
+        );
+        for rotation in ["", "-90", "450", "!", "ninety"] {
+            assert_eq!(
+                rotation.parse::<Rotation>().map_err(|err| err.status()),
+                Err(http::StatusCode::BAD_REQUEST),
+                "{rotation}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_image_requests() {
+        let Ok(IiifRequest::Image {
+            target,
+            image_request,
+        }) = iiif().resolve("/a%2Fb.tif/full/max/!90/gray.png")
+        else {
+            panic!("expected an image request");
+        };
+
+        assert_eq!(target.as_str(), "https://archive.example.com/scans/a/b.tif");
+        let params = image_request.params((40, 30)).unwrap();
+        assert_eq!(params.rotate, Some(90));
+        assert_eq!(params.flip, Some(Flip::Horizontal));
+        assert_eq!(params.gray, Some(true));
+        assert_eq!((params.width, params.height), (None, None));
+
+        assert!(iiif().resolve("/..%2Fsecret.tif/info.json").is_err());
+        assert!(iiif().resolve("/a.tif/full/max/0/bitonal.png").is_err());
+    }
+
+    #[test]
+    fn lists_limits_in_info() {
+        let info = iiif().set_max_width(1000).set_max_area(500_000).info(
+            "id",
+            (4000, 3000),
+            std::iter::empty(),
+        );
+        let info: serde_json::Value = serde_json::from_str(&info).unwrap();
+
+        assert_eq!(info["maxWidth"], 1000);
+        assert_eq!(info["maxArea"], 500_000);
+        assert!(info.get("maxHeight").is_none());
+
+        let info = iiif().info("id", (4000, 3000), std::iter::empty());
+        let info: serde_json::Value = serde_json::from_str(&info).unwrap();
+
+        assert_eq!(info["maxWidth"], 4096);
+        assert_eq!(info["maxArea"], 4096 * 4096);
+    }
+
+    #[test]
+    fn limits_upscaling_by_default() {
+        let params = |path: &str| match iiif().resolve(path) {
+            Ok(IiifRequest::Image { image_request, .. }) => image_request.params((40, 30)),
+            _ => panic!("expected an image request"),
+        };
+
+        let upscaled = params("/a.tif/full/^4096,/0/default.png").unwrap();
+        assert_eq!((upscaled.width, upscaled.height), (Some(4096), Some(3072)));
+
+        for size in ["^65535,65535", "^4097,", "^,4097", "^pct:100000"] {
+            assert_eq!(
+                params(&format!("/a.tif/full/{size}/0/default.png")).map_err(|err| err.status()),
+                Err(http::StatusCode::BAD_REQUEST),
+                "{size}"
+            );
+        }
+        let max = params("/a.tif/full/^max/0/default.png").unwrap();
+        assert_eq!((max.width, max.height), (Some(4096), Some(3072)));
+    }
+}
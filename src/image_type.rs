@@ -1,5 +1,7 @@
 //! Image types as constants which can be used to establish a slice of supported
 //! image types and their respective image formats.
+use std::{fmt, str::FromStr};
+
 use image::ImageFormat;
 use mediatype::{names, MediaType};
 
@@ -13,6 +15,9 @@ const fn image_type(subtype: mediatype::Name) -> MediaType {
 }
 
 /// WebP image type.
+///
+/// WebP is always encoded losslessly, so quality and byte budgets can't reduce
+/// its size other than by downscaling.
 pub const WEBP: SupportedImageType = SupportedImageType::new(IMAGE_WEBP, ImageFormat::WebP);
 /// AVIF image type.
 pub const AVIF: SupportedImageType = SupportedImageType::new(IMAGE_AVIF, ImageFormat::Avif);
@@ -25,6 +30,9 @@ pub const JPEG: SupportedImageType = SupportedImageType::new(IMAGE_JPEG, ImageFo
 pub type SupportedImageTypes = &'static [SupportedImageType<'static>];
 
 /// Default of supported image types, consisting of [`WEBP`] and [`PNG`].
+///
+/// Both are encoded losslessly, so [`JPEG`] or [`AVIF`] must be added for
+/// photos to be encoded lossily.
 pub const DEFAULT_SUPPORTED_IMAGE_TYPES: SupportedImageTypes = &[WEBP, PNG];
 
 /// Pair of [`MediaType`] and [`ImageFormat`].
@@ -54,3 +62,41 @@ impl<'a> From<&'a SupportedImageType<'a>> for &'a MediaType<'a> {
         &value.media_type
     }
 }
+
+/// Output format requested for a transformed image.
+///
+/// This is set per URL via the `f_{format}` parameter, where `{format}` is
+/// either `auto` or a file extension, such as `webp` or `png`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Negotiate the format with the client, preferring lossless formats for
+    /// graphics and lossy formats for photos.
+    #[default]
+    Auto,
+
+    /// Use the given format, provided it's supported.
+    Format(ImageFormat),
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        if format == "auto" {
+            return Ok(Self::Auto);
+        }
+
+        ImageFormat::from_extension(format)
+            .map(Self::Format)
+            .ok_or("Invalid output format")
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Format(format) => f.write_str(format.extensions_str().first().ok_or(fmt::Error)?),
+        }
+    }
+}
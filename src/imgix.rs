@@ -0,0 +1,409 @@
+//! imgix URLs, so that templates written against imgix keep working.
+use std::str::FromStr;
+
+use md5::{Digest, Md5};
+use subtle::ConstantTimeEq;
+use url::{form_urlencoded, Url};
+
+use crate::{
+    geometry,
+    image_type::OutputFormat,
+    resize::Fit,
+    signed::{VerifiedUrl, VerifyError},
+    source::{self, with_trailing_slash},
+    transformation_params::{invalid_value, ParamsError, TransformationParams},
+};
+
+/// Length of an MD5 digest, in bytes.
+const DIGEST_LENGTH: usize = 16;
+
+/// Quality which `auto=compress` defaults to.
+const COMPRESS_QUALITY: u8 = 45;
+
+/// Maximum device pixel ratio, as accepted by imgix.
+const MAX_DPR: f64 = 5.0;
+
+/// Maximum output width and height, as rendered by imgix.
+const MAX_DIMENSION: u32 = 8192;
+
+/// Configuration of imgix URLs.
+///
+/// Requests carry the path of the image, resolved against the source, and
+/// the transform as query parameters, e.g.
+/// `/images/hero.jpg?w=300&h=200&fit=crop&auto=format,compress&dpr=2`.
+///
+/// The `w`, `h`, `dpr`, `q`, `fm`, `fit`, and `crop` parameters are
+/// supported, as are the `format` and `compress` values of `auto`. Output
+/// dimensions are capped at 8192 pixels, as by imgix.
+///
+/// The `clip`, `crop`, `scale`, and `max` values of `fit` map directly onto
+/// [`Fit`], whereas `fill` and `clamp` fit within the dimensions without
+/// padding, `fillmax` does so without enlarging, `min` crops like `crop`, and
+/// `facearea` falls back to `clip`. Crops are taken around the centre, which
+/// every value of `crop` falls back to. Other parameters are ignored, as by
+/// imgix, whereas values which cannot be parsed are rejected.
+///
+/// When a token is set, requests must be signed, as by secure imgix sources,
+/// via the `s` parameter, which must come last.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::Imgix;
+///
+/// let imgix = Imgix::new("https://images.example.com/".parse().unwrap()).set_token("aaAAbbBB");
+/// assert_eq!(
+///     imgix.sign("/hero.jpg?w=300"),
+///     "/hero.jpg?w=300&s=e73d868c960060cd5d1299c82e264217",
+/// );
+/// ```
+#[derive(Clone)]
+pub struct Imgix {
+    source: Url,
+    token: Option<String>,
+}
+
+impl Imgix {
+    /// Create a new [`Imgix`], given the URL paths are resolved against.
+    pub fn new(source: Url) -> Self {
+        Self {
+            source: with_trailing_slash(source),
+            token: None,
+        }
+    }
+
+    /// Set the token which requests must be signed with.
+    ///
+    /// By default, requests are not signed.
+    pub fn set_token(self, token: impl Into<String>) -> Self {
+        Self {
+            token: Some(token.into()),
+            ..self
+        }
+    }
+
+    /// Signs a path and query, e.g. `/hero.jpg?w=300`, returning them with the
+    /// `s` parameter appended.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no token is set.
+    pub fn sign(&self, path_and_query: &str) -> String {
+        let token = self.token.as_deref().expect("token must be set to sign");
+        let signature: String = Md5::digest(format!("{token}{path_and_query}"))
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        let separator = if path_and_query.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{path_and_query}{separator}s={signature}")
+    }
+
+    /// Verifies the path and query of a request, relative to where the service
+    /// is mounted, and resolves them into transform parameters and a target
+    /// URL.
+    pub(crate) fn resolve(
+        &self,
+        path: &str,
+        query: Option<&str>,
+    ) -> Result<VerifiedUrl, VerifyError> {
+        let (query, signature) = split_signature(query.unwrap_or_default());
+
+        if let Some(token) = &self.token {
+            let signature = signature.ok_or(VerifyError::Unsigned)?;
+            let value = match query {
+                "" => format!("{token}{path}"),
+                _ => format!("{token}{path}?{query}"),
+            };
+            verify(&value, signature)?;
+        }
+
+        let (mut width, mut height, mut fit, mut crop, mut format, mut quality, mut auto, mut dpr) =
+            (None, None, None, None, None, None, None, None);
+
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let slot = match &*key {
+                "w" => &mut width,
+                "h" => &mut height,
+                "fit" => &mut fit,
+                "crop" => &mut crop,
+                "fm" => &mut format,
+                "q" => &mut quality,
+                "auto" => &mut auto,
+                "dpr" => &mut dpr,
+                _ => continue,
+            };
+
+            if slot.replace(value).is_some() {
+                return Err(ParamsError::Duplicate(key.into_owned()).into());
+            }
+        }
+
+        let dpr = parse("dpr", dpr.as_deref(), |dpr: &f64| {
+            *dpr > 0.0 && *dpr <= MAX_DPR
+        })?
+        .unwrap_or(1.0);
+        let width = parse("w", width.as_deref(), |width: &u32| *width > 0)?;
+        let height = parse("h", height.as_deref(), |height: &u32| *height > 0)?;
+        let (width, height) = scale_dimensions(width, height, dpr);
+
+        let fit = fit
+            .as_deref()
+            .map(|value| match value {
+                "clip" => Ok(Fit::Contain),
+                "crop" => Ok(Fit::Cover),
+                "scale" => Ok(Fit::Fill),
+                "max" => Ok(Fit::ScaleDown),
+                "fill" | "clamp" | "fillmax" | "min" | "facearea" => {
+                    tracing::debug!(fit = value, "approximating imgix fit");
+                    Ok(match value {
+                        "fillmax" => Fit::ScaleDown,
+                        "min" => Fit::Cover,
+                        _ => Fit::Contain,
+                    })
+                }
+                _ => Err(invalid_value("fit", value)),
+            })
+            .transpose()?
+            .unwrap_or(Fit::Contain);
+
+        if let Some(crop) = crop.as_deref() {
+            if !crop.split(',').all(|mode| {
+                matches!(
+                    mode,
+                    "top"
+                        | "bottom"
+                        | "left"
+                        | "right"
+                        | "faces"
+                        | "focalpoint"
+                        | "edges"
+                        | "entropy"
+                        | "center"
+                )
+            }) {
+                return Err(invalid_value("crop", crop).into());
+            }
+
+            if crop != "center" {
+                tracing::debug!(crop, "cropping around the centre instead");
+            }
+        }
+
+        let auto: Vec<&str> = auto
+            .as_deref()
+            .map_or(Vec::new(), |auto| auto.split(',').collect());
+
+        let (format, progressive) = match format.as_deref() {
+            Some("pjpg") => (
+                Some(OutputFormat::Format(image::ImageFormat::Jpeg)),
+                Some(true),
+            ),
+            Some(value) => (
+                Some(OutputFormat::Format(
+                    image::ImageFormat::from_extension(value)
+                        .ok_or_else(|| invalid_value("fm", value))?,
+                )),
+                None,
+            ),
+            None => (auto.contains(&"format").then_some(OutputFormat::Auto), None),
+        };
+
+        let quality = parse("q", quality.as_deref(), |quality: &u8| *quality <= 100)?
+            .map(|quality| quality.max(1))
+            .or(auto.contains(&"compress").then_some(COMPRESS_QUALITY));
+
+        Ok(VerifiedUrl {
+            params: TransformationParams {
+                width,
+                height,
+                fit: (width.is_some() || height.is_some()).then_some(fit),
+                progressive,
+                quality,
+                format,
+                ..Default::default()
+            },
+            target: self.target(path)?,
+        })
+    }
+
+    fn target(&self, path: &str) -> Result<Url, VerifyError> {
+        source::resolve(&self.source, path.trim_start_matches('/'))
+            .ok_or(VerifyError::InvalidTarget)
+    }
+}
+
+impl std::fmt::Debug for Imgix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Imgix")
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Scales dimensions by the device pixel ratio, and then down to at most
+/// [`MAX_DIMENSION`], keeping their aspect ratio.
+fn scale_dimensions(
+    width: Option<u32>,
+    height: Option<u32>,
+    dpr: f64,
+) -> (Option<u32>, Option<u32>) {
+    geometry::scale_down(
+        width.map(|width| f64::from(width) * dpr),
+        height.map(|height| f64::from(height) * dpr),
+        MAX_DIMENSION,
+    )
+}
+
+/// Splits the `s` parameter, if it comes last, off the query.
+fn split_signature(query: &str) -> (&str, Option<&str>) {
+    let (rest, last) = query.rsplit_once('&').unwrap_or(("", query));
+    match last.strip_prefix("s=") {
+        Some(signature) => (rest, Some(signature)),
+        None => (query, None),
+    }
+}
+
+/// Verifies that `signature` is the hex encoded MD5 digest of `value`.
+fn verify(value: &str, signature: &str) -> Result<(), VerifyError> {
+    if !signature.len().is_multiple_of(2) || !signature.bytes().all(|byte| byte.is_ascii_hexdigit())
+    {
+        return Err(VerifyError::MalformedEncoding);
+    }
+
+    let digest: Vec<u8> = (0..signature.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&signature[index..index + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| VerifyError::MalformedEncoding)?;
+
+    if digest.len() != DIGEST_LENGTH {
+        return Err(VerifyError::WrongLength {
+            expected: DIGEST_LENGTH,
+            actual: digest.len(),
+        });
+    }
+
+    bool::from(Md5::digest(value).as_slice().ct_eq(&digest))
+        .then_some(())
+        .ok_or(VerifyError::Mismatch)
+}
+
+fn parse<T: FromStr>(
+    key: &str,
+    value: Option<&str>,
+    in_range: impl FnOnce(&T) -> bool,
+) -> Result<Option<T>, ParamsError> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .ok()
+                .filter(in_range)
+                .ok_or_else(|| invalid_value(key, value))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imgix() -> Imgix {
+        Imgix::new("https://images.example.com/".parse().unwrap())
+    }
+
+    fn params(query: &str) -> Result<TransformationParams, VerifyError> {
+        imgix()
+            .resolve("/hero.jpg", Some(query))
+            .map(|verified_url| verified_url.params)
+    }
+
+    #[test]
+    fn signs_known_vectors() {
+        // From the imgix documentation on securing images.
+        let imgix = imgix().set_token("FOO123bar");
+
+        assert_eq!(
+            imgix.sign("/users/1.png"),
+            "/users/1.png?s=6797c24146142d5b40bde3141fd3600c"
+        );
+        assert_eq!(
+            imgix.sign("/users/1.png?w=400&h=300"),
+            "/users/1.png?w=400&h=300&s=c7b86f666a832434dd38577e38cf86d1"
+        );
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let imgix = imgix().set_token("FOO123bar");
+
+        assert!(imgix
+            .resolve(
+                "/users/1.png",
+                Some("w=400&h=300&s=c7b86f666a832434dd38577e38cf86d1")
+            )
+            .is_ok());
+        assert_eq!(
+            imgix
+                .resolve(
+                    "/users/1.png",
+                    Some("w=401&h=300&s=c7b86f666a832434dd38577e38cf86d1")
+                )
+                .unwrap_err(),
+            VerifyError::Mismatch
+        );
+        assert_eq!(
+            imgix
+                .resolve("/users/1.png", Some("w=400&h=300"))
+                .unwrap_err(),
+            VerifyError::Unsigned
+        );
+    }
+
+    #[test]
+    fn caps_dimensions() {
+        let dimensions = |query: &str| {
+            let params = params(query).unwrap();
+            (params.width, params.height)
+        };
+
+        assert_eq!(dimensions("w=300&h=200&dpr=2"), (Some(600), Some(400)));
+        assert_eq!(dimensions("w=4000&h=2000&dpr=4"), (Some(8192), Some(4096)));
+        assert_eq!(dimensions("h=10000"), (None, Some(8192)));
+    }
+
+    #[test]
+    fn maps_fits() {
+        for (fit, expected) in [
+            ("clip", Fit::Contain),
+            ("crop", Fit::Cover),
+            ("scale", Fit::Fill),
+            ("max", Fit::ScaleDown),
+            ("fill", Fit::Contain),
+            ("clamp", Fit::Contain),
+            ("fillmax", Fit::ScaleDown),
+            ("min", Fit::Cover),
+            ("facearea", Fit::Contain),
+        ] {
+            assert_eq!(
+                params(&format!("w=100&fit={fit}")).unwrap().fit,
+                Some(expected),
+                "{fit}"
+            );
+        }
+
+        assert!(params("w=100&fit=stretch").is_err());
+    }
+
+    #[test]
+    fn accepts_crops() {
+        assert!(params("w=100&fit=crop&crop=top,left").is_ok());
+        assert!(params("w=100&fit=crop&crop=faces,entropy").is_ok());
+        assert!(params("w=100&fit=crop&crop=middle").is_err());
+    }
+}
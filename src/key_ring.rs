@@ -0,0 +1,125 @@
+use crate::Key;
+
+/// Set of keys used for signing and verifying URLs.
+///
+/// URLs are signed with the signing key, while verification also accepts any of
+/// the verification keys. This allows keys to be rotated without invalidating
+/// URLs which were signed with a previous key.
+///
+/// Keys may be given an ID, which prefixes the signatures they produce so that
+/// verification only has to try the matching key.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::{Key, KeyRing};
+///
+/// # /*
+/// let (current, previous) = { /* cryptographically random keys >= 64 bytes */ };
+/// # */
+/// # let (current, previous) = (Key::generate(), Key::generate());
+///
+/// let key_ring = KeyRing::new(current)
+///     .set_key_id("2024-06")
+///     .add_verification_key_with_id("2024-01", previous);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeyRing {
+    signing: RingKey,
+    verification: Vec<RingKey>,
+}
+
+#[derive(Debug, Clone)]
+struct RingKey {
+    id: Option<String>,
+    key: Key,
+}
+
+impl KeyRing {
+    /// Create a new [`KeyRing`] with the provided signing [`Key`].
+    pub fn new(signing_key: Key) -> Self {
+        Self {
+            signing: RingKey {
+                id: None,
+                key: signing_key,
+            },
+            verification: Vec::new(),
+        }
+    }
+
+    /// Set the ID of the signing key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key_id` is empty or holds characters other than ASCII
+    /// alphanumerics, `-`, and `_`.
+    pub fn set_key_id(self, key_id: impl Into<String>) -> Self {
+        Self {
+            signing: RingKey {
+                id: Some(valid_key_id(key_id.into())),
+                ..self.signing
+            },
+            ..self
+        }
+    }
+
+    /// Add a key which is accepted when verifying signatures without a key ID.
+    pub fn add_verification_key(mut self, key: Key) -> Self {
+        self.verification.push(RingKey { id: None, key });
+        self
+    }
+
+    /// Add a key which is accepted when verifying signatures, either with the
+    /// given key ID or without a key ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key_id` is empty or holds characters other than ASCII
+    /// alphanumerics, `-`, and `_`.
+    pub fn add_verification_key_with_id(mut self, key_id: impl Into<String>, key: Key) -> Self {
+        self.verification.push(RingKey {
+            id: Some(valid_key_id(key_id.into())),
+            key,
+        });
+        self
+    }
+
+    /// Returns the signing key.
+    pub fn signing_key(&self) -> &Key {
+        &self.signing.key
+    }
+
+    /// Returns the ID of the signing key.
+    pub fn key_id(&self) -> Option<&str> {
+        self.signing.id.as_deref()
+    }
+
+    /// Returns the keys which may verify a signature with the given key ID, or
+    /// every key when there is none.
+    pub(crate) fn verification_keys<'a>(
+        &'a self,
+        key_id: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Key> {
+        std::iter::once(&self.signing)
+            .chain(&self.verification)
+            .filter(move |ring_key| key_id.is_none() || ring_key.id.as_deref() == key_id)
+            .map(|ring_key| &ring_key.key)
+    }
+}
+
+impl From<Key> for KeyRing {
+    fn from(signing_key: Key) -> Self {
+        Self::new(signing_key)
+    }
+}
+
+fn valid_key_id(key_id: String) -> String {
+    assert!(
+        !key_id.is_empty()
+            && key_id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+        "key ID must be non-empty and consist of ASCII alphanumerics, `-`, and `_`"
+    );
+    key_id
+}
@@ -63,13 +63,46 @@
 )]
 #![forbid(unsafe_code)]
 
+mod cipher;
+mod color;
+mod content;
+mod dzi;
+mod encode;
+mod geometry;
+mod iiif;
 pub mod image_type;
+mod imgix;
 mod key;
+mod key_ring;
+mod metadata;
+mod negotiate;
+mod next_image;
+mod quantize;
+mod resize;
+mod responsive;
 mod service;
 mod signed;
+mod source;
+mod thumbor;
 mod transformation_params;
+mod unsigned;
 
-pub use image_type::{SupportedImageTypes, DEFAULT_SUPPORTED_IMAGE_TYPES};
+pub use dzi::Dzi;
+pub use geometry::{Crop, Flip};
+pub use iiif::Iiif;
+pub use image_type::{OutputFormat, SupportedImageTypes, DEFAULT_SUPPORTED_IMAGE_TYPES};
+pub use imgix::Imgix;
 pub use key::Key;
-pub use service::ImageTransformerBuilder;
-pub use signed::{SignedUrlBuilder, Verifier};
+pub use key_ring::KeyRing;
+pub use metadata::MetadataPolicy;
+pub use next_image::{NextImage, RemotePattern};
+pub use resize::Fit;
+pub use responsive::{Picture, SrcsetError};
+pub use service::{ImageTransformerBuilder, Passthrough, Protocol};
+pub use signed::{
+    SignedUrl, SignedUrlBuilder, TargetEncoding, UrlLayout, UrlSigner, VerifiedUrl, Verifier,
+    VerifyError,
+};
+pub use thumbor::Thumbor;
+pub use transformation_params::{Height, ParamsError, TransformationParams, Width};
+pub use unsigned::UnsignedPolicy;
@@ -0,0 +1,264 @@
+//! Handling of image metadata, such as EXIF and ICC profiles, on output.
+use std::{fmt, io::Cursor, str::FromStr};
+
+use image::{ImageDecoder, ImageEncoder, ImageResult};
+
+/// Policy describing which metadata is carried over from the source image to
+/// the transformed image.
+///
+/// Metadata is stripped by default: user uploads frequently carry sensitive
+/// EXIF data, such as GPS coordinates. XMP metadata is never carried over.
+///
+/// The policy may be configured on the server via
+/// [`ImageTransformerBuilder::set_metadata_policy`](crate::ImageTransformerBuilder::set_metadata_policy)
+/// and overridden per URL via the `md_{policy}` parameter, where `{policy}` is
+/// one of `strip`, `icc`, or `copyright`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataPolicy {
+    /// Strip all metadata.
+    #[default]
+    Strip,
+
+    /// Keep the ICC colour profile.
+    ///
+    /// When the image is converted to sRGB, the sRGB profile is kept instead.
+    KeepIcc,
+
+    /// Keep the ICC colour profile as well as the copyright and artist EXIF
+    /// tags.
+    KeepCopyright,
+}
+
+impl MetadataPolicy {
+    pub(crate) const fn keeps_icc(self) -> bool {
+        matches!(self, Self::KeepIcc | Self::KeepCopyright)
+    }
+
+    const fn keeps_copyright(self) -> bool {
+        matches!(self, Self::KeepCopyright)
+    }
+}
+
+impl FromStr for MetadataPolicy {
+    type Err = &'static str;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "strip" => Ok(Self::Strip),
+            "icc" => Ok(Self::KeepIcc),
+            "copyright" => Ok(Self::KeepCopyright),
+            _ => Err("Invalid metadata policy"),
+        }
+    }
+}
+
+impl fmt::Display for MetadataPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let policy = match self {
+            Self::Strip => "strip",
+            Self::KeepIcc => "icc",
+            Self::KeepCopyright => "copyright",
+        };
+        f.write_str(policy)
+    }
+}
+
+/// Metadata retained from a source image, in accordance with a
+/// [`MetadataPolicy`].
+#[derive(Debug, Default)]
+pub(crate) struct Metadata {
+    pub(crate) icc_profile: Option<Vec<u8>>,
+    pub(crate) exif: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the metadata permitted by `policy` from the given decoder.
+    pub(crate) fn read(
+        decoder: &mut impl ImageDecoder,
+        policy: MetadataPolicy,
+    ) -> ImageResult<Self> {
+        let icc_profile = if policy.keeps_icc() {
+            decoder.icc_profile()?
+        } else {
+            None
+        };
+
+        let exif = if policy.keeps_copyright() {
+            decoder.exif_metadata()?.and_then(copyright_exif)
+        } else {
+            None
+        };
+
+        Ok(Self { icc_profile, exif })
+    }
+
+    /// Attaches the retained metadata to the given encoder.
+    ///
+    /// Not every format is able to carry every kind of metadata; in that case
+    /// the metadata is dropped.
+    pub(crate) fn apply(&self, encoder: &mut impl ImageEncoder) {
+        if let Some(icc_profile) = &self.icc_profile {
+            if let Err(err) = encoder.set_icc_profile(icc_profile.clone()) {
+                tracing::debug!(err = %err, "could not embed ICC profile");
+            }
+        }
+
+        if let Some(exif) = &self.exif {
+            if let Err(err) = encoder.set_exif_metadata(exif.clone()) {
+                tracing::debug!(err = %err, "could not embed EXIF metadata");
+            }
+        }
+    }
+}
+
+/// Builds a minimal EXIF blob containing only the copyright and artist tags of
+/// the given EXIF blob.
+fn copyright_exif(exif: Vec<u8>) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new()
+        .read_raw(exif)
+        .inspect_err(|err| tracing::warn!(err = %err, "could not parse EXIF metadata"))
+        .ok()?;
+
+    let fields: Vec<&exif::Field> = [exif::Tag::Copyright, exif::Tag::Artist]
+        .into_iter()
+        .filter_map(|tag| exif.get_field(tag, exif::In::PRIMARY))
+        .collect();
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+
+    let mut buf = Cursor::new(Vec::new());
+    writer
+        .write(&mut buf, exif.little_endian())
+        .inspect_err(|err| tracing::warn!(err = %err, "could not write EXIF metadata"))
+        .ok()?;
+
+    Some(buf.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        codecs::png::{PngDecoder, PngEncoder},
+        ExtendedColorType,
+    };
+
+    use super::*;
+
+    const ICC_PROFILE: &[u8] = b"not really a colour profile";
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"></x:xmpmeta>"#;
+
+    fn ascii_field(tag: exif::Tag, value: &str) -> exif::Field {
+        exif::Field {
+            tag,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    /// Returns a PNG carrying an ICC profile, XMP, and EXIF with the
+    /// copyright, artist, and camera make tags.
+    fn source() -> Vec<u8> {
+        let fields = [
+            ascii_field(exif::Tag::Copyright, "Jane Doe"),
+            ascii_field(exif::Tag::Artist, "John Doe"),
+            ascii_field(exif::Tag::Make, "Camera Co"),
+        ];
+        let mut writer = exif::experimental::Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+
+        let mut info = png::Info::with_size(1, 1);
+        info.color_type = png::ColorType::Grayscale;
+        info.icc_profile = Some(ICC_PROFILE.into());
+        info.exif_metadata = Some(exif.into_inner().into());
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::with_info(&mut png, info).unwrap();
+        encoder
+            .add_itxt_chunk("XML:com.adobe.xmp".to_owned(), XMP.to_owned())
+            .unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0]).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    /// Carries the metadata of the source over as `policy` permits, returning
+    /// the decoder of the output.
+    fn round_trip(policy: MetadataPolicy) -> PngDecoder<Cursor<Vec<u8>>> {
+        let mut decoder = PngDecoder::new(Cursor::new(source())).unwrap();
+        let metadata = Metadata::read(&mut decoder, policy).unwrap();
+
+        let mut output = Vec::new();
+        let mut encoder = PngEncoder::new(&mut output);
+        metadata.apply(&mut encoder);
+        encoder
+            .write_image(&[0], 1, 1, ExtendedColorType::L8)
+            .unwrap();
+
+        PngDecoder::new(Cursor::new(output)).unwrap()
+    }
+
+    fn tags(exif: Vec<u8>) -> Vec<exif::Tag> {
+        let exif = exif::Reader::new().read_raw(exif).unwrap();
+        exif.fields().map(|field| field.tag).collect()
+    }
+
+    #[test]
+    fn source_carries_all_metadata() {
+        let mut decoder = PngDecoder::new(Cursor::new(source())).unwrap();
+
+        assert_eq!(decoder.icc_profile().unwrap().unwrap(), ICC_PROFILE);
+        assert!(decoder.xmp_metadata().unwrap().is_some());
+        assert_eq!(
+            tags(decoder.exif_metadata().unwrap().unwrap()),
+            [exif::Tag::Make, exif::Tag::Artist, exif::Tag::Copyright]
+        );
+    }
+
+    #[test]
+    fn strip_removes_all_metadata() {
+        let mut decoder = round_trip(MetadataPolicy::Strip);
+
+        assert_eq!(decoder.icc_profile().unwrap(), None);
+        assert_eq!(decoder.exif_metadata().unwrap(), None);
+        assert_eq!(decoder.xmp_metadata().unwrap(), None);
+    }
+
+    #[test]
+    fn keep_icc_keeps_only_the_icc_profile() {
+        let mut decoder = round_trip(MetadataPolicy::KeepIcc);
+
+        assert_eq!(decoder.icc_profile().unwrap().unwrap(), ICC_PROFILE);
+        assert_eq!(decoder.exif_metadata().unwrap(), None);
+        assert_eq!(decoder.xmp_metadata().unwrap(), None);
+    }
+
+    #[test]
+    fn keep_copyright_keeps_only_copyright_and_artist() {
+        let mut decoder = round_trip(MetadataPolicy::KeepCopyright);
+
+        assert_eq!(decoder.icc_profile().unwrap().unwrap(), ICC_PROFILE);
+        assert_eq!(decoder.xmp_metadata().unwrap(), None);
+
+        let exif = decoder.exif_metadata().unwrap().unwrap();
+        let exif = exif::Reader::new().read_raw(exif).unwrap();
+        let value = |tag| {
+            exif.get_field(tag, exif::In::PRIMARY)
+                .map(|field| field.display_value().to_string())
+        };
+        assert_eq!(value(exif::Tag::Copyright).as_deref(), Some("\"Jane Doe\""));
+        assert_eq!(value(exif::Tag::Artist).as_deref(), Some("\"John Doe\""));
+        assert_eq!(value(exif::Tag::Make), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Negotiation of the output format with the client's `Accept` header.
+use headers_accept::Accept;
+use image::ImageFormat;
+use mediatype::{names, MediaType, MediaTypeBuf, ReadParams};
+
+use crate::image_type::SupportedImageType;
+
+/// Client preference for a single media type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Preference {
+    /// The `q` value, where zero means not acceptable.
+    quality: f32,
+
+    /// Whether the media type was named explicitly, rather than matched by a
+    /// wildcard.
+    explicit: bool,
+}
+
+/// Returns the formats the client prefers most, in the order the server prefers
+/// them.
+///
+/// Every supported format is weighted by the `q` value of the most specific
+/// media range in `accept` which matches it, and a missing header accepts any
+/// format. When the client is indifferent, i.e. it only accepts the most
+/// preferred formats via wildcards, `default_format` is chosen if it's among
+/// them.
+///
+/// An empty result means none of the supported formats are acceptable.
+pub(crate) fn preferred_formats(
+    accept: Option<&Accept>,
+    supported_image_types: &[SupportedImageType<'_>],
+    default_format: Option<ImageFormat>,
+) -> Vec<ImageFormat> {
+    let weighted: Vec<(ImageFormat, Preference)> = supported_image_types
+        .iter()
+        .map(|supported| {
+            let preference = accept.map_or(
+                Preference {
+                    quality: 1.0,
+                    explicit: false,
+                },
+                |accept| preference(accept, &supported.media_type),
+            );
+            (supported.image_format, preference)
+        })
+        .filter(|(_, preference)| preference.quality > 0.0)
+        .collect();
+
+    let Some(best) = weighted
+        .iter()
+        .map(|(_, preference)| preference.quality)
+        .reduce(f32::max)
+    else {
+        return Vec::new();
+    };
+
+    let preferred: Vec<(ImageFormat, Preference)> = weighted
+        .into_iter()
+        .filter(|(_, preference)| preference.quality == best)
+        .collect();
+
+    let indifferent = preferred.iter().all(|(_, preference)| !preference.explicit);
+
+    match default_format {
+        Some(default_format)
+            if indifferent
+                && preferred
+                    .iter()
+                    .any(|&(format, _)| format == default_format) =>
+        {
+            vec![default_format]
+        }
+
+        _ => preferred.into_iter().map(|(format, _)| format).collect(),
+    }
+}
+
+/// Returns the preference `accept` expresses for `media_type`.
+fn preference(accept: &Accept, media_type: &MediaType<'_>) -> Preference {
+    // Media ranges are ordered from most to least specific, so the first match
+    // takes precedence.
+    accept
+        .media_types()
+        .find(|range| matches(range, media_type))
+        .map_or(
+            Preference {
+                quality: 0.0,
+                explicit: false,
+            },
+            |range| Preference {
+                quality: range
+                    .get_param(names::Q)
+                    .and_then(|q| q.as_str().parse().ok())
+                    .unwrap_or(1.0),
+                explicit: range.subty() != names::_STAR,
+            },
+        )
+}
+
+fn matches(range: &MediaTypeBuf, media_type: &MediaType<'_>) -> bool {
+    range.ty() == names::_STAR
+        || range.ty() == media_type.ty
+            && (range.subty() == names::_STAR || range.subty() == media_type.subty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_type::{AVIF, JPEG, PNG, WEBP};
+
+    const SUPPORTED: &[SupportedImageType<'static>] = &[WEBP, AVIF, PNG];
+
+    fn negotiate(accept: Option<&str>, default_format: Option<ImageFormat>) -> Vec<ImageFormat> {
+        let accept = accept.map(|accept| accept.parse::<Accept>().unwrap());
+        preferred_formats(accept.as_ref(), SUPPORTED, default_format)
+    }
+
+    #[test]
+    fn honours_q_values() {
+        assert_eq!(
+            negotiate(Some("image/webp;q=0.5, image/png;q=0.8"), None),
+            [ImageFormat::Png]
+        );
+        assert_eq!(
+            negotiate(Some("image/*;q=0.2, image/avif"), None),
+            [ImageFormat::Avif]
+        );
+    }
+
+    #[test]
+    fn excludes_formats_with_zero_q_values() {
+        assert_eq!(
+            negotiate(Some("image/*, image/webp;q=0"), None),
+            [ImageFormat::Avif, ImageFormat::Png]
+        );
+        assert!(negotiate(Some("image/*;q=0"), None).is_empty());
+    }
+
+    #[test]
+    fn explicit_types_take_precedence_over_wildcards() {
+        // The explicit type's q value applies, even where a wildcard's is
+        // higher.
+        assert_eq!(
+            negotiate(Some("image/*, image/avif;q=0.1, image/webp;q=0.1"), None),
+            [ImageFormat::Png]
+        );
+        // Naming a type explicitly means the client isn't indifferent, so the
+        // default isn't forced on it.
+        assert_eq!(
+            negotiate(Some("image/*, image/avif"), Some(ImageFormat::Png)),
+            [ImageFormat::WebP, ImageFormat::Avif, ImageFormat::Png]
+        );
+    }
+
+    #[test]
+    fn breaks_ties_by_server_preference() {
+        assert_eq!(
+            negotiate(Some("image/png, image/webp"), None),
+            [ImageFormat::WebP, ImageFormat::Png]
+        );
+        assert_eq!(
+            negotiate(Some("image/*"), None),
+            [ImageFormat::WebP, ImageFormat::Avif, ImageFormat::Png]
+        );
+    }
+
+    #[test]
+    fn picks_the_default_for_indifferent_clients() {
+        for accept in [
+            None,
+            Some("*/*"),
+            Some("image/*"),
+            Some("image/*, text/html"),
+        ] {
+            assert_eq!(
+                negotiate(accept, Some(ImageFormat::Png)),
+                [ImageFormat::Png],
+                "{accept:?}"
+            );
+        }
+        assert_eq!(
+            negotiate(None, None),
+            [ImageFormat::WebP, ImageFormat::Avif, ImageFormat::Png]
+        );
+    }
+
+    #[test]
+    fn ignores_defaults_the_client_does_not_prefer() {
+        assert_eq!(
+            negotiate(Some("image/*;q=0.5, image/webp"), Some(ImageFormat::Png)),
+            [ImageFormat::WebP]
+        );
+        assert_eq!(
+            negotiate(Some("image/*"), Some(ImageFormat::Jpeg)),
+            [ImageFormat::WebP, ImageFormat::Avif, ImageFormat::Png]
+        );
+    }
+
+    #[test]
+    fn accepts_nothing_when_no_supported_type_matches() {
+        assert!(negotiate(Some("image/jpeg"), None).is_empty());
+        assert!(negotiate(Some("text/html"), None).is_empty());
+        assert!(preferred_formats(None, &[], None).is_empty());
+        assert_eq!(
+            preferred_formats(Some(&"image/jpeg".parse().unwrap()), &[JPEG], None),
+            [ImageFormat::Jpeg]
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! The Next.js image optimizer protocol, as spoken by `/_next/image`.
+use url::{form_urlencoded, Url};
+
+use crate::{
+    resize::Fit,
+    signed::VerifiedUrl,
+    transformation_params::{TransformationParams, Width},
+};
+
+/// Default widths of the `deviceSizes` configuration of Next.js.
+const DEFAULT_DEVICE_SIZES: [Width; 8] = [640, 750, 828, 1080, 1200, 1920, 2048, 3840];
+
+/// Default widths of the `imageSizes` configuration of Next.js.
+const DEFAULT_IMAGE_SIZES: [Width; 8] = [16, 32, 48, 64, 96, 128, 256, 384];
+
+/// Configuration of the Next.js image optimizer protocol.
+///
+/// Requests carry the target URL, width, and quality as query parameters, e.g.
+/// `?url=%2Fimages%2Fhero.png&w=640&q=75`, as generated by the default loader
+/// of `next/image`. The width must be one of the configured device or image
+/// sizes, and images are never enlarged.
+///
+/// Relative target URLs are resolved against the origin, if one is set, and
+/// absolute target URLs must match one of the remote patterns.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::{NextImage, RemotePattern};
+///
+/// let next_image = NextImage::new()
+///     .set_origin("https://www.example.com".parse().unwrap())
+///     .add_remote_pattern(
+///         RemotePattern::new("**.example.com")
+///             .set_protocol("https")
+///             .set_pathname("/images/**"),
+///     );
+/// ```
+#[derive(Debug, Clone)]
+pub struct NextImage {
+    origin: Option<Url>,
+    remote_patterns: Vec<RemotePattern>,
+    device_sizes: Vec<Width>,
+    image_sizes: Vec<Width>,
+    qualities: Option<Vec<u8>>,
+}
+
+impl Default for NextImage {
+    fn default() -> Self {
+        Self {
+            origin: None,
+            remote_patterns: Vec::new(),
+            device_sizes: DEFAULT_DEVICE_SIZES.to_vec(),
+            image_sizes: DEFAULT_IMAGE_SIZES.to_vec(),
+            qualities: None,
+        }
+    }
+}
+
+impl NextImage {
+    /// Create a new [`NextImage`] with the default sizes of Next.js.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the origin which relative target URLs, e.g. `/images/hero.png`,
+    /// are resolved against.
+    ///
+    /// Relative target URLs are rejected unless an origin is set.
+    pub fn set_origin(self, origin: Url) -> Self {
+        Self {
+            origin: Some(origin),
+            ..self
+        }
+    }
+
+    /// Allow absolute target URLs which match the given pattern.
+    pub fn add_remote_pattern(mut self, remote_pattern: RemotePattern) -> Self {
+        self.remote_patterns.push(remote_pattern);
+        self
+    }
+
+    /// Set the widths of the `deviceSizes` configuration.
+    pub fn set_device_sizes(self, device_sizes: Vec<Width>) -> Self {
+        Self {
+            device_sizes,
+            ..self
+        }
+    }
+
+    /// Set the widths of the `imageSizes` configuration.
+    pub fn set_image_sizes(self, image_sizes: Vec<Width>) -> Self {
+        Self {
+            image_sizes,
+            ..self
+        }
+    }
+
+    /// Set the qualities which may be requested, as in the `qualities`
+    /// configuration.
+    ///
+    /// By default, any quality from 1 to 100 may be requested.
+    pub fn set_qualities(self, qualities: Vec<u8>) -> Self {
+        Self {
+            qualities: Some(qualities),
+            ..self
+        }
+    }
+
+    /// Resolves the query of a request into transform parameters and a target
+    /// URL.
+    pub(crate) fn resolve(&self, query: Option<&str>) -> Result<VerifiedUrl, &'static str> {
+        let (mut url, mut width, mut quality) = (None, None, None);
+
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            let slot = match &*key {
+                "url" => &mut url,
+                "w" => &mut width,
+                "q" => &mut quality,
+                _ => continue,
+            };
+
+            if slot.replace(value).is_some() {
+                return Err("parameters must not be repeated");
+            }
+        }
+
+        let url = url.ok_or("\"url\" parameter is required")?;
+        let width = width.ok_or("\"w\" parameter (width) is required")?;
+        let quality = quality.ok_or("\"q\" parameter (quality) is required")?;
+
+        // Backslashes are taken as slashes, so `/\host` would be
+        // protocol-relative.
+        if url.contains('\\') {
+            return Err("\"url\" parameter is invalid");
+        }
+
+        let target = if url.starts_with('/') && !url.starts_with("//") {
+            let origin = self
+                .origin
+                .as_ref()
+                .ok_or("\"url\" parameter is relative, but no origin is configured")?;
+            let target = origin
+                .join(&url)
+                .map_err(|_| "\"url\" parameter is invalid")?;
+
+            if target.origin() != origin.origin() {
+                return Err("\"url\" parameter is not allowed");
+            }
+            target
+        } else {
+            let target: Url = url.parse().map_err(|_| "\"url\" parameter is invalid")?;
+            if !matches!(target.scheme(), "http" | "https")
+                || !self
+                    .remote_patterns
+                    .iter()
+                    .any(|remote_pattern| remote_pattern.matches(&target))
+            {
+                return Err("\"url\" parameter is not allowed");
+            }
+            target
+        };
+
+        let width: Width = width
+            .parse()
+            .ok()
+            .filter(|width| self.device_sizes.contains(width) || self.image_sizes.contains(width))
+            .ok_or("\"w\" parameter (width) is not allowed")?;
+
+        let quality: u8 = quality
+            .parse()
+            .ok()
+            .filter(|quality| (1..=100).contains(quality))
+            .filter(|quality| {
+                self.qualities
+                    .as_ref()
+                    .is_none_or(|qualities| qualities.contains(quality))
+            })
+            .ok_or("\"q\" parameter (quality) is not allowed")?;
+
+        Ok(VerifiedUrl {
+            params: TransformationParams {
+                width: Some(width),
+                fit: Some(Fit::ScaleDown),
+                quality: Some(quality),
+                ..Default::default()
+            },
+            target,
+        })
+    }
+}
+
+/// Pattern of absolute target URLs, as in the `remotePatterns` configuration of
+/// Next.js.
+///
+/// Within the hostname, `*` matches a single subdomain and `**` any number of
+/// subdomains. Likewise, within the pathname, `*` matches a single path
+/// segment and `**` any number of path segments.
+#[derive(Debug, Clone)]
+pub struct RemotePattern {
+    protocol: Option<String>,
+    hostname: String,
+    port: Option<u16>,
+    pathname: Option<String>,
+}
+
+impl RemotePattern {
+    /// Create a new [`RemotePattern`] matching the given hostname, e.g.
+    /// `images.example.com` or `**.example.com`.
+    pub fn new(hostname: impl Into<String>) -> Self {
+        Self {
+            protocol: None,
+            hostname: hostname.into().to_ascii_lowercase(),
+            port: None,
+            pathname: None,
+        }
+    }
+
+    /// Only match the given protocol, e.g. `https`.
+    pub fn set_protocol(self, protocol: impl Into<String>) -> Self {
+        Self {
+            protocol: Some(protocol.into()),
+            ..self
+        }
+    }
+
+    /// Only match the given port.
+    pub fn set_port(self, port: u16) -> Self {
+        Self {
+            port: Some(port),
+            ..self
+        }
+    }
+
+    /// Only match the given pathname, e.g. `/images/**`.
+    pub fn set_pathname(self, pathname: impl Into<String>) -> Self {
+        Self {
+            pathname: Some(pathname.into()),
+            ..self
+        }
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        self.protocol
+            .as_ref()
+            .is_none_or(|protocol| protocol == url.scheme())
+            && self
+                .port
+                .is_none_or(|port| url.port_or_known_default() == Some(port))
+            && url.host_str().is_some_and(|host| {
+                matches_glob(&segments(&self.hostname, '.'), &segments(host, '.'))
+            })
+            && self.pathname.as_ref().is_none_or(|pathname| {
+                matches_glob(&segments(pathname, '/'), &segments(url.path(), '/'))
+            })
+    }
+}
+
+fn segments(value: &str, separator: char) -> Vec<&str> {
+    value.split(separator).collect()
+}
+
+/// Returns whether `value` matches `pattern`, segment by segment, where `*`
+/// matches a single segment and `**` any number of segments.
+fn matches_glob(pattern: &[&str], value: &[&str]) -> bool {
+    match (pattern.split_first(), value.split_first()) {
+        (None, None) => true,
+
+        (Some((&"**", pattern_rest)), _) => {
+            matches_glob(pattern_rest, value)
+                || value
+                    .split_first()
+                    .is_some_and(|(_, value_rest)| matches_glob(pattern, value_rest))
+        }
+
+        (Some((&pattern_segment, pattern_rest)), Some((&value_segment, value_rest))) => {
+            (pattern_segment == "*" || pattern_segment == value_segment)
+                && matches_glob(pattern_rest, value_rest)
+        }
+
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next_image() -> NextImage {
+        NextImage::new().set_origin("https://www.example.com".parse().unwrap())
+    }
+
+    fn resolve(next_image: &NextImage, url: &str) -> Result<Url, &'static str> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("url", url)
+            .append_pair("w", "640")
+            .append_pair("q", "75")
+            .finish();
+        next_image
+            .resolve(Some(&query))
+            .map(|verified_url| verified_url.target)
+    }
+
+    #[test]
+    fn resolves_relative_urls_against_the_origin() {
+        assert_eq!(
+            resolve(&next_image(), "/images/hero.png").unwrap().as_str(),
+            "https://www.example.com/images/hero.png"
+        );
+    }
+
+    #[test]
+    fn rejects_relative_urls_escaping_the_origin() {
+        for url in [
+            "/\\evil.example.net/a.png",
+            "/\\/evil.example.net/a.png",
+            "//evil.example.net/a.png",
+        ] {
+            assert!(resolve(&next_image(), url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn matches_absolute_urls_against_remote_patterns() {
+        let next_image = next_image().add_remote_pattern(
+            RemotePattern::new("**.example.com")
+                .set_protocol("https")
+                .set_pathname("/images/**"),
+        );
+
+        assert!(resolve(&next_image, "https://cdn.example.com/images/a/b.png").is_ok());
+        assert!(resolve(&next_image, "http://cdn.example.com/images/a.png").is_err());
+        assert!(resolve(&next_image, "https://cdn.example.com/other/a.png").is_err());
+        assert!(resolve(&next_image, "https://cdn.example.net/images/a.png").is_err());
+    }
+
+    #[test]
+    fn matches_globs() {
+        let glob = |pattern: &str, value: &str| {
+            matches_glob(&segments(pattern, '/'), &segments(value, '/'))
+        };
+
+        assert!(glob("/a/b", "/a/b"));
+        assert!(!glob("/a/b", "/a/b/"));
+        assert!(!glob("/a/b", "/a"));
+
+        // `*` matches exactly one segment, which may be empty.
+        assert!(glob("/a/*", "/a/b"));
+        assert!(glob("/a/*", "/a/"));
+        assert!(!glob("/a/*", "/a/b/c"));
+        assert!(!glob("/a/*", "/a"));
+
+        // `**` matches any number of segments, including none.
+        assert!(glob("/a/**", "/a/b/c"));
+        assert!(glob("/a/**", "/a"));
+        assert!(glob("/**/c", "/a/b/c"));
+        assert!(glob("/**/c", "/c"));
+        assert!(!glob("/**/c", "/a/b"));
+        assert!(glob("**", ""));
+        assert!(!glob("", "a"));
+    }
+}
@@ -0,0 +1,263 @@
+//! Palette quantization, used to produce compact indexed PNG output.
+use color_quant::NeuQuant;
+use image::{imageops, imageops::colorops::ColorMap, DynamicImage, Rgba};
+
+/// Smallest palette which may be requested.
+pub(crate) const MIN_COLORS: u16 = 2;
+
+/// Largest palette which may be requested.
+pub(crate) const MAX_COLORS: u16 = 256;
+
+/// Smallest palette NeuQuant is designed for; smaller palettes are reduced
+/// from one of this size.
+const MIN_NEUQUANT_COLORS: u16 = 64;
+
+/// Sampling factor of the quantizer; lower is slower but more accurate.
+const SAMPLE_FACTOR: i32 = 10;
+
+/// Options for reducing an image to an indexed palette.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PaletteOptions {
+    /// Number of colours in the palette.
+    pub(crate) colors: u16,
+
+    /// Whether Floyd-Steinberg dithering is applied.
+    pub(crate) dither: bool,
+}
+
+/// Image reduced to an indexed palette.
+#[derive(Debug)]
+pub(crate) struct IndexedImage {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+
+    /// RGBA palette entries.
+    pub(crate) palette: Vec<[u8; 4]>,
+
+    /// One palette index per pixel, in row-major order.
+    pub(crate) indices: Vec<u8>,
+}
+
+impl IndexedImage {
+    /// Returns the smallest PNG bit depth which can address every palette
+    /// entry.
+    pub(crate) fn bit_depth(&self) -> u8 {
+        match self.palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        }
+    }
+}
+
+/// Quantizes `image` to a palette of at most `options.colors` colours.
+pub(crate) fn quantize(image: &DynamicImage, options: PaletteOptions) -> IndexedImage {
+    let colors = options.colors.clamp(MIN_COLORS, MAX_COLORS);
+    let mut rgba = image.to_rgba8();
+    let quantizer = NeuQuant::new(SAMPLE_FACTOR, colors.max(MIN_NEUQUANT_COLORS).into(), &rgba);
+
+    let mut palette: Vec<[u8; 4]> = quantizer
+        .color_map_rgba()
+        .chunks_exact(4)
+        .map(|entry| [entry[0], entry[1], entry[2], entry[3]])
+        .collect();
+
+    if palette.len() > usize::from(colors) {
+        let mut counts = vec![0u64; palette.len()];
+        for index in imageops::index_colors(&rgba, &quantizer).iter() {
+            counts[usize::from(*index)] += 1;
+        }
+        // Entries no pixel maps to would only skew the reduced palette.
+        let used = palette
+            .into_iter()
+            .zip(counts)
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        palette = median_cut(used, usize::from(colors));
+    }
+
+    let palette = Palette(palette);
+    if options.dither {
+        imageops::dither(&mut rgba, &palette);
+    }
+
+    IndexedImage {
+        width: rgba.width(),
+        height: rgba.height(),
+        indices: imageops::index_colors(&rgba, &palette).into_raw(),
+        palette: palette.0,
+    }
+}
+
+/// Reduces colours, weighted by their pixel counts, to at most `colors`
+/// colours by repeatedly splitting the box of colours with the widest channel
+/// at its weighted median.
+fn median_cut(entries: Vec<([u8; 4], u64)>, colors: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![entries];
+
+    while boxes.len() < colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, entries)| entries.len() > 1)
+            .map(|(index, entries)| {
+                let (channel, range) = widest_channel(entries);
+                (index, channel, range)
+            })
+            .max_by_key(|(.., range)| *range);
+        let Some((index, channel, _)) = widest else {
+            break;
+        };
+
+        let mut entries = boxes.swap_remove(index);
+        entries.sort_by_key(|(color, _)| color[channel]);
+
+        let total: u64 = entries.iter().map(|(_, count)| count).sum();
+        let mut seen = 0;
+        let median = entries
+            .iter()
+            .position(|(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap_or_default()
+            .min(entries.len() - 2);
+
+        let upper = entries.split_off(median + 1);
+        boxes.push(entries);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|entries| mean(entries)).collect()
+}
+
+/// Returns the channel along which `entries` spread the most, and its range.
+fn widest_channel(entries: &[([u8; 4], u64)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = entries.iter().map(|(color, _)| color[channel]);
+            let range = values.clone().max().unwrap_or_default() - values.min().unwrap_or_default();
+            (channel, range)
+        })
+        .max_by_key(|(_, range)| *range)
+        .expect("There are always channels")
+}
+
+/// Returns the mean of `entries`, weighted by their pixel counts.
+fn mean(entries: &[([u8; 4], u64)]) -> [u8; 4] {
+    let total: u64 = entries.iter().map(|(_, count)| count).sum::<u64>().max(1);
+
+    std::array::from_fn(|channel| {
+        let sum: u64 = entries
+            .iter()
+            .map(|(color, count)| u64::from(color[channel]) * count)
+            .sum();
+        ((sum + total / 2) / total) as u8
+    })
+}
+
+/// Palette which maps colours to the nearest entry.
+struct Palette(Vec<[u8; 4]>);
+
+impl ColorMap for Palette {
+    type Color = Rgba<u8>;
+
+    fn index_of(&self, color: &Rgba<u8>) -> usize {
+        let distance = |entry: &[u8; 4]| -> u32 {
+            entry
+                .iter()
+                .zip(color.0)
+                .map(|(&a, b)| u32::from(a.abs_diff(b)).pow(2))
+                .sum()
+        };
+
+        self.0
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| distance(entry))
+            .map_or(0, |(index, _)| index)
+    }
+
+    fn lookup(&self, index: usize) -> Option<Rgba<u8>> {
+        self.0.get(index).copied().map(Rgba)
+    }
+
+    fn has_lookup(&self) -> bool {
+        true
+    }
+
+    fn map_color(&self, color: &mut Rgba<u8>) {
+        if let Some(entry) = self.lookup(self.index_of(color)) {
+            *color = entry;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255])
+        }))
+    }
+
+    #[test]
+    fn limits_palettes_to_the_requested_colors() {
+        for colors in [2, 4, 16, 63, 64, 256] {
+            for dither in [false, true] {
+                let indexed = quantize(&gradient(), PaletteOptions { colors, dither });
+
+                assert!(indexed.palette.len() <= usize::from(colors));
+                assert_eq!(indexed.indices.len(), 64 * 64);
+                assert!(indexed
+                    .indices
+                    .iter()
+                    .all(|&index| usize::from(index) < indexed.palette.len()));
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_distinct_colors_of_small_palettes() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, _| {
+            if x < 8 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        }));
+
+        let indexed = quantize(
+            &image,
+            PaletteOptions {
+                colors: 2,
+                dither: false,
+            },
+        );
+
+        let mut palette = indexed.palette.clone();
+        palette.sort();
+        assert_eq!(palette, [[0, 0, 0, 255], [255, 255, 255, 255]]);
+        assert_ne!(indexed.indices[0], indexed.indices[15]);
+    }
+
+    #[test]
+    fn median_cut_splits_at_the_weighted_median() {
+        let entries = vec![
+            ([0, 0, 0, 255], 10),
+            ([10, 0, 0, 255], 10),
+            ([200, 0, 0, 255], 1),
+            ([250, 0, 0, 255], 1),
+        ];
+
+        let mut palette = median_cut(entries, 2);
+        palette.sort();
+
+        assert_eq!(palette, [[5, 0, 0, 255], [225, 0, 0, 255]]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Resizing of images to the requested dimensions.
+use std::{fmt, str::FromStr};
+
+use image::{imageops::FilterType, DynamicImage};
+
+use crate::transformation_params::{Height, Width};
+
+/// How an image is fit to the requested dimensions.
+///
+/// This is set per URL via the `fit_{fit}` parameter, where `{fit}` is one of
+/// `fill`, `contain`, `cover`, or `scale-down`, after the CSS `object-fit`
+/// property. A dimension which is not requested is unconstrained, except for
+/// [`Fit::Fill`], which keeps it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Stretch the image to exactly the requested dimensions.
+    #[default]
+    Fill,
+
+    /// Scale the image to fit within the requested dimensions, keeping its
+    /// aspect ratio.
+    Contain,
+
+    /// Scale the image to cover the requested dimensions, keeping its aspect
+    /// ratio, and crop it to them around its centre.
+    Cover,
+
+    /// Like [`Fit::Contain`], but never enlarge the image.
+    ScaleDown,
+}
+
+impl FromStr for Fit {
+    type Err = &'static str;
+
+    fn from_str(fit: &str) -> Result<Self, Self::Err> {
+        match fit {
+            "fill" => Ok(Self::Fill),
+            "contain" => Ok(Self::Contain),
+            "cover" => Ok(Self::Cover),
+            "scale-down" => Ok(Self::ScaleDown),
+            _ => Err("Invalid fit"),
+        }
+    }
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fit = match self {
+            Self::Fill => "fill",
+            Self::Contain => "contain",
+            Self::Cover => "cover",
+            Self::ScaleDown => "scale-down",
+        };
+        f.write_str(fit)
+    }
+}
+
+/// Returns the dimensions an image of `dimensions` is resized to.
+pub(crate) fn resized_dimensions(
+    dimensions: (u32, u32),
+    width: Option<Width>,
+    height: Option<Height>,
+    fit: Fit,
+) -> (u32, u32) {
+    let (image_width, image_height) = dimensions;
+
+    if image_width == 0 || image_height == 0 {
+        return dimensions;
+    }
+
+    let scale_x = width.map(|width| f64::from(width) / f64::from(image_width));
+    let scale_y = height.map(|height| f64::from(height) / f64::from(image_height));
+
+    let scale = match (fit, scale_x, scale_y) {
+        (Fit::Fill, _, _) | (Fit::Cover, Some(_), Some(_)) => {
+            return (width.unwrap_or(image_width), height.unwrap_or(image_height))
+        }
+        (_, None, None) => return dimensions,
+        (_, Some(scale), None) | (_, None, Some(scale)) => scale,
+        (_, Some(scale_x), Some(scale_y)) => scale_x.min(scale_y),
+    };
+
+    if fit == Fit::ScaleDown && scale >= 1.0 {
+        return dimensions;
+    }
+
+    let scaled = |dimension: u32| ((f64::from(dimension) * scale).round() as u32).max(1);
+    (scaled(image_width), scaled(image_height))
+}
+
+/// Resizes `image` to the requested dimensions.
+pub(crate) fn resize(
+    image: DynamicImage,
+    width: Option<Width>,
+    height: Option<Height>,
+    fit: Fit,
+) -> DynamicImage {
+    let dimensions = (image.width(), image.height());
+    let (resized_width, resized_height) = resized_dimensions(dimensions, width, height, fit);
+
+    if (resized_width, resized_height) == dimensions {
+        return image;
+    }
+
+    match fit {
+        Fit::Cover => image.resize_to_fill(resized_width, resized_height, FilterType::Lanczos3),
+        _ => image.resize_exact(resized_width, resized_height, FilterType::Lanczos3),
+    }
+}
@@ -0,0 +1,452 @@
+//! Generation of `srcset` attributes and `<picture>` markup for responsive
+//! images.
+use std::fmt::Write as _;
+
+use image::ImageFormat;
+use url::Url;
+
+use crate::{
+    image_type::OutputFormat,
+    transformation_params::{TransformationParams, Width},
+    UrlSigner,
+};
+
+/// Reason a `srcset` attribute or `<picture>` markup could not be generated.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum SrcsetError {
+    /// A signed URL could not be built.
+    #[error("failed to build signed URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    /// A width is zero.
+    #[error("width must be greater than zero")]
+    ZeroWidth,
+
+    /// A density is not positive and finite.
+    #[error("density `{0}` is not positive and finite")]
+    InvalidDensity(f32),
+
+    /// Densities are given, but the transform parameters set neither a width
+    /// nor a height to scale by them.
+    #[error("densities require a width or height to scale")]
+    MissingDimensions,
+}
+
+/// Candidates of a `srcset` attribute.
+#[derive(Debug, Clone, PartialEq)]
+enum Candidates {
+    /// Widths, in pixels, described as e.g. `640w`.
+    Widths(Vec<Width>),
+
+    /// Pixel densities, described as e.g. `2x`.
+    Densities(Vec<f32>),
+}
+
+impl Candidates {
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Widths(widths) => widths.is_empty(),
+            Self::Densities(densities) => densities.is_empty(),
+        }
+    }
+}
+
+impl UrlSigner {
+    /// Generates a `srcset` attribute value with a signed URL per width, e.g.
+    /// `{url} 320w, {url} 640w`.
+    ///
+    /// When `params` sets both a width and a height, the height is scaled
+    /// along with the width so that the aspect ratio is kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a width is zero.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use tower_image_xform::{Key, TransformationParams, UrlSigner};
+    /// use url::Url;
+    ///
+    /// let signer = UrlSigner::new(
+    ///     Key::generate(),
+    ///     Url::parse("https://example.com/_image/").unwrap(),
+    /// );
+    /// let target =
+    ///     Url::parse("https://www.rustacean.net/assets/rustacean-orig-noshadow.png").unwrap();
+    ///
+    /// let srcset = signer
+    ///     .srcset(&TransformationParams::default(), &target, &[320, 640])
+    ///     .unwrap();
+    /// ```
+    pub fn srcset(
+        &self,
+        params: &TransformationParams,
+        target: &Url,
+        widths: &[Width],
+    ) -> Result<String, SrcsetError> {
+        self.candidates_srcset(params, target, &Candidates::Widths(widths.to_vec()))
+    }
+
+    /// Generates a `srcset` attribute value with a signed URL per pixel
+    /// density, e.g. `{url} 1x, {url} 2x`.
+    ///
+    /// The width and height set in `params` are those at a density of `1x`, and
+    /// are scaled by each density.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a density is not positive and finite, or if
+    /// `params` sets neither a width nor a height.
+    pub fn density_srcset(
+        &self,
+        params: &TransformationParams,
+        target: &Url,
+        densities: &[f32],
+    ) -> Result<String, SrcsetError> {
+        self.candidates_srcset(params, target, &Candidates::Densities(densities.to_vec()))
+    }
+
+    fn candidates_srcset(
+        &self,
+        params: &TransformationParams,
+        target: &Url,
+        candidates: &Candidates,
+    ) -> Result<String, SrcsetError> {
+        let candidates: Vec<(TransformationParams, String)> = match candidates {
+            Candidates::Widths(widths) => widths
+                .iter()
+                .map(|&width| Ok((scale_to_width(params, width)?, format!("{width}w"))))
+                .collect::<Result<_, SrcsetError>>()?,
+
+            Candidates::Densities(densities) => densities
+                .iter()
+                .map(|&density| Ok((scale_by_density(params, density)?, format!("{density}x"))))
+                .collect::<Result<_, SrcsetError>>()?,
+        };
+
+        let mut srcset = String::new();
+        for (params, descriptor) in candidates {
+            if !srcset.is_empty() {
+                srcset.push_str(", ");
+            }
+            let url = self.sign(&params, target)?;
+            write!(srcset, "{url} {descriptor}").expect("Writing to a string cannot fail");
+        }
+
+        Ok(srcset)
+    }
+}
+
+/// `<picture>` markup with a `<source>` per output format and an `<img>`
+/// fallback.
+///
+/// Each `<source>` fixes its output format via the `f` parameter, so the
+/// formats given must be supported by the server.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::{Key, Picture, TransformationParams, UrlSigner};
+/// use url::Url;
+///
+/// let signer = UrlSigner::new(
+///     Key::generate(),
+///     Url::parse("https://example.com/_image/").unwrap(),
+/// );
+/// let target =
+///     Url::parse("https://www.rustacean.net/assets/rustacean-orig-noshadow.png").unwrap();
+///
+/// let html = Picture::new(signer, TransformationParams::default(), target)
+///     .set_widths(vec![320, 640, 1280])
+///     .set_sizes("(max-width: 640px) 100vw, 640px")
+///     .set_alt("Ferris")
+///     .to_html()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Picture {
+    signer: UrlSigner,
+    params: TransformationParams,
+    target: Url,
+    candidates: Candidates,
+    sizes: Option<String>,
+    formats: Vec<ImageFormat>,
+    fallback_format: ImageFormat,
+    alt: String,
+}
+
+impl Picture {
+    /// Create a new [`Picture`] of `target`, transformed with `params`.
+    pub fn new(signer: UrlSigner, params: TransformationParams, target: Url) -> Self {
+        Self {
+            signer,
+            params,
+            target,
+            candidates: Candidates::Widths(Vec::new()),
+            sizes: None,
+            formats: vec![ImageFormat::WebP],
+            fallback_format: ImageFormat::Png,
+            alt: String::new(),
+        }
+    }
+
+    /// Set the widths offered by each `srcset`.
+    ///
+    /// Without widths or densities, each `srcset` holds a single URL.
+    pub fn set_widths(self, widths: Vec<Width>) -> Self {
+        Self {
+            candidates: Candidates::Widths(widths),
+            ..self
+        }
+    }
+
+    /// Set the pixel densities offered by each `srcset`, instead of widths.
+    ///
+    /// These are validated by [`Picture::to_html`].
+    pub fn set_densities(self, densities: Vec<f32>) -> Self {
+        Self {
+            candidates: Candidates::Densities(densities),
+            ..self
+        }
+    }
+
+    /// Set the `sizes` attribute, which tells the browser which width the
+    /// image is displayed at.
+    pub fn set_sizes(self, sizes: impl Into<String>) -> Self {
+        Self {
+            sizes: Some(sizes.into()),
+            ..self
+        }
+    }
+
+    /// Set the formats of the `<source>` elements, in order of preference.
+    ///
+    /// Defaults to WebP, which the server supports by default.
+    pub fn set_formats(self, formats: Vec<ImageFormat>) -> Self {
+        Self { formats, ..self }
+    }
+
+    /// Set the format of the `<img>` fallback.
+    ///
+    /// Defaults to PNG, which the server supports by default.
+    pub fn set_fallback_format(self, fallback_format: ImageFormat) -> Self {
+        Self {
+            fallback_format,
+            ..self
+        }
+    }
+
+    /// Set the alternative text of the `<img>` fallback.
+    pub fn set_alt(self, alt: impl Into<String>) -> Self {
+        Self {
+            alt: alt.into(),
+            ..self
+        }
+    }
+
+    /// Generates the `<picture>` markup.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a width is zero, a density is not positive and
+    /// finite, or densities are set while the transform parameters set
+    /// neither a width nor a height.
+    pub fn to_html(&self) -> Result<String, SrcsetError> {
+        let sizes = match (&self.candidates, &self.sizes) {
+            (candidates @ Candidates::Widths(_), Some(sizes)) if !candidates.is_empty() => {
+                format!(r#" sizes="{}""#, escape(sizes))
+            }
+            _ => String::new(),
+        };
+
+        let mut html = String::from("<picture>\n");
+
+        for &format in &self.formats {
+            let srcset = self.srcset(format)?;
+            writeln!(
+                html,
+                r#"  <source type="{}" srcset="{}"{sizes}>"#,
+                format.to_mime_type(),
+                escape(&srcset)
+            )
+            .expect("Writing to a string cannot fail");
+        }
+
+        let src = self
+            .signer
+            .sign(&self.with_format(self.fallback_format), &self.target)?;
+        let srcset = if self.candidates.is_empty() {
+            String::new()
+        } else {
+            format!(
+                r#" srcset="{}""#,
+                escape(&self.srcset(self.fallback_format)?)
+            )
+        };
+        writeln!(
+            html,
+            r#"  <img src="{}"{srcset}{sizes} alt="{}">"#,
+            escape(src.as_str()),
+            escape(&self.alt)
+        )
+        .expect("Writing to a string cannot fail");
+
+        html.push_str("</picture>");
+
+        Ok(html)
+    }
+
+    fn srcset(&self, format: ImageFormat) -> Result<String, SrcsetError> {
+        let params = self.with_format(format);
+        if self.candidates.is_empty() {
+            Ok(self.signer.sign(&params, &self.target)?.into())
+        } else {
+            self.signer
+                .candidates_srcset(&params, &self.target, &self.candidates)
+        }
+    }
+
+    fn with_format(&self, format: ImageFormat) -> TransformationParams {
+        TransformationParams {
+            format: Some(OutputFormat::Format(format)),
+            ..self.params.clone()
+        }
+    }
+}
+
+fn scale_to_width(
+    params: &TransformationParams,
+    width: Width,
+) -> Result<TransformationParams, SrcsetError> {
+    if width == 0 {
+        return Err(SrcsetError::ZeroWidth);
+    }
+
+    let height = match (params.width, params.height) {
+        (Some(original_width @ 1..), Some(height)) => {
+            let scaled = u64::from(height) * u64::from(width) / u64::from(original_width);
+            Some(u32::try_from(scaled).unwrap_or(u32::MAX).max(1))
+        }
+        (_, height) => height,
+    };
+
+    Ok(TransformationParams {
+        width: Some(width),
+        height,
+        ..params.clone()
+    })
+}
+
+fn scale_by_density(
+    params: &TransformationParams,
+    density: f32,
+) -> Result<TransformationParams, SrcsetError> {
+    if !(density.is_finite() && density > 0.0) {
+        return Err(SrcsetError::InvalidDensity(density));
+    }
+
+    if params.width.is_none() && params.height.is_none() {
+        return Err(SrcsetError::MissingDimensions);
+    }
+
+    let scale = |dimension: u32| ((dimension as f32 * density).round() as u32).max(1);
+
+    Ok(TransformationParams {
+        width: params.width.map(scale),
+        height: params.height.map(scale),
+        ..params.clone()
+    })
+}
+
+/// Escapes `value` for use within a double-quoted HTML attribute.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(
+            Key::generate(),
+            "https://example.com/_image/".parse().unwrap(),
+        )
+    }
+
+    fn target() -> Url {
+        "https://example.com/image.png".parse().unwrap()
+    }
+
+    fn sized(width: Width, height: u32) -> TransformationParams {
+        let mut params = TransformationParams::default();
+        (params.width, params.height) = (Some(width), Some(height));
+        params
+    }
+
+    #[test]
+    fn scales_height_with_width() {
+        let params = scale_to_width(&sized(400, 300), 200).unwrap();
+
+        assert_eq!((params.width, params.height), (Some(200), Some(150)));
+        assert_eq!(
+            scale_to_width(&sized(400, 300), 0),
+            Err(SrcsetError::ZeroWidth)
+        );
+    }
+
+    #[test]
+    fn scales_dimensions_by_density() {
+        let params = scale_by_density(&sized(100, 50), 1.5).unwrap();
+
+        assert_eq!((params.width, params.height), (Some(150), Some(75)));
+    }
+
+    #[test]
+    fn rejects_invalid_densities() {
+        for density in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                signer().density_srcset(&sized(100, 50), &target(), &[1.0, density]),
+                Err(SrcsetError::InvalidDensity(_))
+            ));
+        }
+
+        let picture = Picture::new(signer(), sized(100, 50), target()).set_densities(vec![0.0]);
+        assert!(matches!(
+            picture.to_html(),
+            Err(SrcsetError::InvalidDensity(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_densities_without_dimensions() {
+        assert_eq!(
+            signer().density_srcset(&TransformationParams::default(), &target(), &[1.0, 2.0]),
+            Err(SrcsetError::MissingDimensions)
+        );
+    }
+
+    #[test]
+    fn defaults_to_formats_supported_by_default() {
+        let html = Picture::new(signer(), TransformationParams::default(), target())
+            .to_html()
+            .unwrap();
+
+        assert!(html.contains(r#"<source type="image/webp""#));
+        assert!(!html.contains("image/avif"));
+        assert!(html.contains("f_png"));
+    }
+}
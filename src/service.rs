@@ -1,9 +1,12 @@
 use std::{
+    collections::HashMap,
     convert::Infallible,
     io::{BufWriter, Cursor},
     marker::PhantomData,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::Bytes;
@@ -12,55 +15,178 @@ use headers_accept::Accept;
 use http::{header, HeaderMap, Request, Response};
 use http_body::Body;
 use http_body_util::Full;
-use image::{ImageFormat, ImageReader};
-use percent_encoding::percent_decode_str;
+use image::{metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
 use tokio::task;
 use tower_service::Service;
 use tracing::instrument;
 use url::Url;
 
 use crate::{
-    image_type::{SupportedImageType, SupportedImageTypes, DEFAULT_SUPPORTED_IMAGE_TYPES},
+    color,
+    content::{self, ImageContent},
+    dzi::{Dzi, DziRequest},
+    encode::{encode_to_vec, is_lossy, supports_alpha, ByteBudget, EncodeOptions},
+    geometry,
+    iiif::{Iiif, IiifRequest},
+    image_type::{OutputFormat, SupportedImageTypes, DEFAULT_SUPPORTED_IMAGE_TYPES},
+    imgix::Imgix,
     key::Key,
-    signed::Verifier,
-    transformation_params::TransformationParams,
+    key_ring::KeyRing,
+    metadata::{Metadata, MetadataPolicy},
+    negotiate,
+    next_image::NextImage,
+    quantize::PaletteOptions,
+    resize,
+    signed::{VerifiedUrl, Verifier, VerifyError},
+    thumbor::Thumbor,
+    transformation_params::{is_valid_preset_name, TransformationParams},
+    unsigned::UnsignedPolicy,
 };
 
+/// Maximum age of cached responses, in seconds.
+const MAX_AGE: u64 = 31_536_000;
+
+/// Default time limit of the search for an encoding which satisfies a
+/// `maxbytes` parameter.
+const DEFAULT_MAX_BYTES_TIME_LIMIT: Duration = Duration::from_secs(2);
+
 #[derive(Debug, thiserror::Error)]
 pub enum ImageXformError {
     #[error(transparent)]
     Image(#[from] image::error::ImageError),
 
+    #[error(transparent)]
+    Jpeg(#[from] jpeg_encoder::EncodingError),
+
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+
     #[error(transparent)]
     WriterFinalization(#[from] std::io::IntoInnerError<BufWriter<Cursor<Vec<u8>>>>),
+
+    #[error("crop region is outside of the image")]
+    CropOutOfBounds,
+
+    #[error("could not encode within {max_bytes} bytes (smallest attempt was {smallest} bytes)")]
+    ByteBudgetExceeded { max_bytes: u64, smallest: u64 },
+}
+
+/// Policy for serving the upstream image as-is, instead of re-encoding it.
+///
+/// The upstream image is only eligible when the transformation would not alter
+/// its pixels, its format is acceptable to the client, and it carries no
+/// metadata which would otherwise be stripped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Passthrough {
+    /// Always re-encode.
+    Never,
+
+    /// Serve an eligible upstream image without decoding or re-encoding it.
+    Always,
+
+    /// Re-encode an eligible upstream image, but serve whichever of the two is
+    /// smaller.
+    #[default]
+    Smaller,
+}
+
+/// Protocol which requests to the service follow.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub enum Protocol {
+    /// Signed URLs, as generated by
+    /// [`SignedUrlBuilder`](crate::SignedUrlBuilder)
+    /// and [`UrlSigner`](crate::UrlSigner).
+    #[default]
+    Signed,
+
+    /// The Next.js image optimizer protocol, so that the service may stand in
+    /// for `/_next/image`.
+    NextImage(NextImage),
+
+    /// The IIIF Image API 3.0, for viewers such as Mirador.
+    Iiif(Iiif),
+
+    /// Thumbor URLs, signed with the security key of a Thumbor deployment.
+    Thumbor(Thumbor),
+
+    /// imgix URLs, optionally signed with the token of an imgix source.
+    Imgix(Imgix),
+
+    /// Deep Zoom images, for viewers such as OpenSeadragon.
+    Dzi(Dzi),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TransformOptions {
+    metadata_policy: MetadataPolicy,
+    color: ColorOptions,
+    encode: EncodeOptions,
+    passthrough: Passthrough,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ColorOptions {
+    convert_to_srgb: bool,
+    embed_srgb_profile: bool,
 }
 
 struct TransformedImage {
-    bytes: Vec<u8>,
+    bytes: Bytes,
     format: ImageFormat,
 }
 
 #[derive(Debug, Clone)]
 pub struct ImageTransformer<ResBody = Full<Bytes>> {
-    client: reqwest::Client,
-    verifier: Verifier,
-    supported_image_types: SupportedImageTypes,
+    handler: Arc<Handler>,
 
     // Covariant over ResBody; no dropping of ResBody.
     _marker: PhantomData<fn() -> ResBody>,
 }
 
+/// Configuration of an [`ImageTransformer`], which handles its requests.
+#[derive(Debug)]
+struct Handler {
+    client: reqwest::Client,
+    verifier: Verifier,
+    supported_image_types: SupportedImageTypes,
+    metadata_policy: MetadataPolicy,
+    color: ColorOptions,
+    progressive: bool,
+    max_bytes_downscale: bool,
+    max_bytes_time_limit: Duration,
+    passthrough: Passthrough,
+    default_format: Option<ImageFormat>,
+    presets: HashMap<String, TransformationParams>,
+    protocol: Protocol,
+}
+
 /// Builder for [`ImageTransformer`].
 #[derive(Debug)]
 pub struct ImageTransformerBuilder {
     client: reqwest::Client,
     verifier: Verifier,
     supported_image_types: SupportedImageTypes,
+    metadata_policy: MetadataPolicy,
+    convert_to_srgb: bool,
+    embed_srgb_profile: bool,
+    progressive: bool,
+    max_bytes_downscale: bool,
+    max_bytes_time_limit: Duration,
+    passthrough: Passthrough,
+    default_format: Option<ImageFormat>,
+    presets: HashMap<String, TransformationParams>,
+    protocol: Protocol,
 }
 
 impl ImageTransformerBuilder {
-    /// Create a new [`ImageTransformerBuilder`] with the provided [`Key`].
-    pub fn new(key: Key) -> Self {
+    /// Create a new [`ImageTransformerBuilder`] with the provided [`Key`] or
+    /// [`KeyRing`].
+    ///
+    /// Encrypted target URLs are decrypted with the signing key, unless
+    /// another key is configured via
+    /// [`ImageTransformerBuilder::set_encryption_key`].
+    pub fn new(key: impl Into<KeyRing>) -> Self {
         let client = reqwest::Client::new();
         let verifier = Verifier::new(key);
 
@@ -68,6 +194,16 @@ impl ImageTransformerBuilder {
             client,
             verifier,
             supported_image_types: DEFAULT_SUPPORTED_IMAGE_TYPES,
+            metadata_policy: MetadataPolicy::default(),
+            convert_to_srgb: true,
+            embed_srgb_profile: false,
+            progressive: false,
+            max_bytes_downscale: false,
+            max_bytes_time_limit: DEFAULT_MAX_BYTES_TIME_LIMIT,
+            passthrough: Passthrough::default(),
+            default_format: None,
+            presets: HashMap::new(),
+            protocol: Protocol::default(),
         }
     }
 
@@ -76,6 +212,18 @@ impl ImageTransformerBuilder {
         Self { client, ..self }
     }
 
+    /// Configure the key which encrypted target URLs are decrypted with.
+    ///
+    /// This must match the key given to
+    /// [`SignedUrlBuilder::encryption_key`](crate::SignedUrlBuilder::encryption_key).
+    /// Defaults to the signing key.
+    pub fn set_encryption_key(self, encryption_key: Key) -> Self {
+        Self {
+            verifier: self.verifier.set_encryption_key(encryption_key),
+            ..self
+        }
+    }
+
     /// Configure supported image types.
     pub fn set_supported_image_types(self, supported_image_types: SupportedImageTypes) -> Self {
         Self {
@@ -84,12 +232,198 @@ impl ImageTransformerBuilder {
         }
     }
 
+    /// Configure the metadata policy.
+    ///
+    /// This may be overridden per URL. Defaults to [`MetadataPolicy::Strip`].
+    pub fn set_metadata_policy(self, metadata_policy: MetadataPolicy) -> Self {
+        Self {
+            metadata_policy,
+            ..self
+        }
+    }
+
+    /// Configure whether images with an embedded ICC profile are converted to
+    /// sRGB before encoding.
+    ///
+    /// Wide-gamut sources, such as Display P3 or Adobe RGB, otherwise appear
+    /// washed out once their profile is dropped. Defaults to `true`.
+    pub fn set_convert_to_srgb(self, convert_to_srgb: bool) -> Self {
+        Self {
+            convert_to_srgb,
+            ..self
+        }
+    }
+
+    /// Configure whether the sRGB profile is embedded in the output.
+    ///
+    /// The profile is only embedded when the pixels are sRGB, i.e. when the
+    /// source is untagged or was converted to sRGB. Defaults to `false`.
+    pub fn set_embed_srgb_profile(self, embed_srgb_profile: bool) -> Self {
+        Self {
+            embed_srgb_profile,
+            ..self
+        }
+    }
+
+    /// Configure whether JPEGs are encoded as progressive and PNGs as
+    /// Adam7-interlaced, so that they render incrementally.
+    ///
+    /// This may be overridden per URL. Defaults to `false`.
+    pub fn set_progressive(self, progressive: bool) -> Self {
+        Self {
+            progressive,
+            ..self
+        }
+    }
+
+    /// Configure whether dimensions may be reduced to satisfy a `maxbytes`
+    /// parameter once reducing quality alone does not suffice.
+    ///
+    /// Defaults to `false`.
+    pub fn set_max_bytes_downscale(self, max_bytes_downscale: bool) -> Self {
+        Self {
+            max_bytes_downscale,
+            ..self
+        }
+    }
+
+    /// Configure how long the search for an encoding which satisfies a
+    /// `maxbytes` parameter may run.
+    ///
+    /// Defaults to two seconds.
+    pub fn set_max_bytes_time_limit(self, max_bytes_time_limit: Duration) -> Self {
+        Self {
+            max_bytes_time_limit,
+            ..self
+        }
+    }
+
+    /// Configure when the upstream image is served as-is.
+    ///
+    /// Defaults to [`Passthrough::Smaller`].
+    pub fn set_passthrough(self, passthrough: Passthrough) -> Self {
+        Self {
+            passthrough,
+            ..self
+        }
+    }
+
+    /// Configure the format which is served when the client has no preference
+    /// among the supported image types, such as when the `Accept` header is
+    /// missing or only holds wildcards.
+    ///
+    /// The format must be one of the supported image types. By default, the
+    /// content of the image decides between the supported image types, in the
+    /// order they were configured.
+    pub fn set_default_format(self, default_format: ImageFormat) -> Self {
+        Self {
+            default_format: Some(default_format),
+            ..self
+        }
+    }
+
+    /// Configure the protocol which requests follow.
+    ///
+    /// Defaults to [`Protocol::Signed`].
+    pub fn set_protocol(self, protocol: Protocol) -> Self {
+        Self { protocol, ..self }
+    }
+
+    /// Configure the policy for accepting unsigned URLs, for clients which
+    /// cannot sign URLs.
+    ///
+    /// By default, unsigned URLs are not accepted.
+    pub fn set_unsigned_policy(self, unsigned_policy: UnsignedPolicy) -> Self {
+        Self {
+            verifier: self.verifier.set_unsigned_policy(unsigned_policy),
+            ..self
+        }
+    }
+
+    /// Register a preset, which URLs refer to by name via the `p` parameter.
+    ///
+    /// Parameters given alongside the preset override those of the preset.
+    /// Since URLs only carry the name, a preset may be changed without
+    /// re-signing them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is empty or holds characters other than ASCII
+    /// alphanumerics, `-`, and `_`, or if `params` sets a preset or an expiry.
+    pub fn add_preset(mut self, name: impl Into<String>, params: TransformationParams) -> Self {
+        let name = name.into();
+        assert!(
+            is_valid_preset_name(&name),
+            "preset name must be non-empty and consist of ASCII alphanumerics, `-`, and `_`"
+        );
+        assert!(
+            params.preset.is_none() && params.expires.is_none(),
+            "preset must not set a preset or an expiry"
+        );
+        self.presets.insert(name, params);
+        self
+    }
+
+    /// Configure how long past their expiry URLs are still accepted, to allow
+    /// for clocks which run behind the clock URLs are signed with.
+    ///
+    /// Defaults to one minute.
+    pub fn set_clock_skew(self, clock_skew: Duration) -> Self {
+        Self {
+            verifier: self.verifier.set_clock_skew(clock_skew),
+            ..self
+        }
+    }
+
     /// Build the [`ImageTransformer`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the default format or the Deep Zoom tile format is not among
+    /// the supported image types, or if unsafe Thumbor URLs are allowed without
+    /// a source.
     pub fn build(self) -> ImageTransformer {
-        ImageTransformer {
+        let handler = Handler {
             client: self.client,
             verifier: self.verifier,
             supported_image_types: self.supported_image_types,
+            metadata_policy: self.metadata_policy,
+            color: ColorOptions {
+                convert_to_srgb: self.convert_to_srgb,
+                embed_srgb_profile: self.embed_srgb_profile,
+            },
+            progressive: self.progressive,
+            max_bytes_downscale: self.max_bytes_downscale,
+            max_bytes_time_limit: self.max_bytes_time_limit,
+            passthrough: self.passthrough,
+            default_format: self.default_format,
+            presets: self.presets,
+            protocol: self.protocol,
+        };
+
+        if let Some(default_format) = handler.default_format {
+            assert!(
+                handler.supports(default_format),
+                "default format must be among the supported image types"
+            );
+        }
+
+        if let Protocol::Thumbor(thumbor) = &handler.protocol {
+            assert!(
+                thumbor.has_valid_unsafe(),
+                "unsafe Thumbor URLs must be restricted to a source"
+            );
+        }
+
+        if let Protocol::Dzi(dzi) = &handler.protocol {
+            assert!(
+                handler.supports(dzi.format()),
+                "Deep Zoom tile format must be among the supported image types"
+            );
+        }
+
+        ImageTransformer {
+            handler: Arc::new(handler),
             _marker: PhantomData,
         }
     }
@@ -110,156 +444,521 @@ where
     }
 
     fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
-        let client = self.client.clone();
-        let supported_image_types = self.supported_image_types;
-        let verifier = self.verifier.clone();
+        let handler = Arc::clone(&self.handler);
 
         Box::pin(async move {
-            // Parse accept header.
-            let accept_header = req.headers().get(header::ACCEPT);
-            let Some::<Accept>(accept) = accept_header.and_then(|v| v.try_into().ok()) else {
-                tracing::error!(
-                    header_value = ?accept_header,
-                    "missing or invalid accept header"
-                );
-                return Ok(response_with_status(http::StatusCode::BAD_REQUEST));
-            };
+            Ok(handler
+                .handle(req)
+                .await
+                .unwrap_or_else(response_with_status))
+        })
+    }
+}
+
+impl Handler {
+    /// Handles a request, returning the status of the response on failure.
+    async fn handle<B>(&self, req: Request<B>) -> Result<Response<Full<Bytes>>, http::StatusCode> {
+        // Parse accept header; a missing header accepts any media type.
+        let accept_header = req.headers().get(header::ACCEPT);
+        let accept = accept_header
+            .map(Accept::try_from)
+            .transpose()
+            .map_err(|_| {
+                tracing::error!(header_value = ?accept_header, "invalid accept header");
+                http::StatusCode::BAD_REQUEST
+            })?;
 
-            let uri = req.uri();
-
-            // Expected format should follow:
-            //
-            //   /{signature}/{transform_param1},...,{transform_paramN}/{image_url}
-            //
-            // This provides three segments:
-            //
-            //   1. HMAC digest (i.e. signature) of the transform parameters and image URL,
-            //   2. Comma-separated transform parameters,
-            //   3. And URL-encoded image URL.
-            //
-            // For example, a valid request might look like:
-            //
-            //   https://example.com/_image/36c6...5xE=/w_100,h_100/https%3A%2F%2Fwww.rustacean.net%2Fassets%2Frustacean-orig-noshadow.png
-            let segments: Vec<&str> = uri.path().trim_start_matches('/').splitn(3, '/').collect();
-
-            if segments.len() != 3 {
-                tracing::error!(uri = %uri, "invalid path");
-                return Ok(response_with_status(http::StatusCode::BAD_REQUEST));
+        let uri = req.uri();
+
+        let verified_url = match &self.protocol {
+            Protocol::Signed => self
+                .verifier
+                .verify_path_and_query(uri.path(), uri.query())
+                .map_err(|err| {
+                    tracing::error!(uri = %uri, err = %err, "could not verify signed URL");
+                    verify_error_status(&err)
+                })?,
+
+            Protocol::NextImage(next_image) => next_image.resolve(uri.query()).map_err(|err| {
+                tracing::error!(uri = %uri, err, "invalid Next.js image request");
+                http::StatusCode::BAD_REQUEST
+            })?,
+
+            Protocol::Iiif(iiif) => {
+                let iiif_request = iiif.resolve(uri.path()).map_err(|err| {
+                    tracing::error!(uri = %uri, err = %err, "invalid IIIF request");
+                    err.status()
+                })?;
+
+                // Viewers are typically served from other origins.
+                return self.iiif(iiif, iiif_request).await.map(with_any_origin);
             }
 
-            let signature = segments[0];
-            let value = [segments[1], segments[2]].concat();
+            Protocol::Thumbor(thumbor) => thumbor.resolve(uri.path()).map_err(|err| {
+                tracing::error!(uri = %uri, err = %err, "could not verify Thumbor URL");
+                verify_error_status(&err)
+            })?,
+
+            Protocol::Imgix(imgix) => imgix.resolve(uri.path(), uri.query()).map_err(|err| {
+                tracing::error!(uri = %uri, err = %err, "could not verify imgix URL");
+                verify_error_status(&err)
+            })?,
 
-            if !verifier.verify(signature, &value) {
-                tracing::error!(uri = %uri, "could not verify signature");
-                return Ok(response_with_status(http::StatusCode::BAD_REQUEST));
+            Protocol::Dzi(dzi) => {
+                let dzi_request = dzi.resolve(uri.path()).map_err(|err| {
+                    tracing::error!(uri = %uri, err, "invalid Deep Zoom request");
+                    http::StatusCode::BAD_REQUEST
+                })?;
+
+                // Viewers are typically served from other origins.
+                return self.dzi(dzi, dzi_request).await.map(with_any_origin);
             }
+        };
 
-            let Ok(transformation_params) = segments[1].parse::<TransformationParams>() else {
-                tracing::error!(uri = %uri, "invalid transformation parameters");
-                return Ok(response_with_status(http::StatusCode::BAD_REQUEST));
-            };
+        self.transform(verified_url, accept.as_ref()).await
+    }
 
-            let Some(target_url) = percent_decode_str(segments[2])
-                .decode_utf8()
-                .ok()
-                .and_then(|decoded| decoded.parse::<Url>().ok())
-            else {
-                tracing::error!(uri = %uri, "invalid target URL");
-                return Ok(response_with_status(http::StatusCode::BAD_REQUEST));
-            };
+    /// Transforms the target of a verified URL in accordance with its
+    /// parameters and the client's `Accept` header.
+    async fn transform(
+        &self,
+        verified_url: VerifiedUrl,
+        accept: Option<&Accept>,
+    ) -> Result<Response<Full<Bytes>>, http::StatusCode> {
+        let VerifiedUrl {
+            params: transformation_params,
+            target: target_url,
+        } = verified_url;
 
-            // Load the image from the provided image URL.
-            let proxy_res = match client.get(target_url).send().await {
-                Err(err) => {
-                    tracing::error!(err = %err, "failed to load image");
-                    return Ok(response_with_status(http::StatusCode::BAD_GATEWAY));
+        let transformation_params = match &transformation_params.preset {
+            Some(name) => match self.presets.get(name) {
+                Some(preset) => transformation_params.with_preset(preset),
+                None => {
+                    tracing::error!(preset = %name, "unknown preset");
+                    return Err(http::StatusCode::BAD_REQUEST);
                 }
+            },
+            None => transformation_params,
+        };
 
-                Ok(proxy_res) => proxy_res,
-            };
+        if let Err(err) = transformation_params.check_dependencies() {
+            tracing::error!(err = %err, "invalid parameters");
+            return Err(http::StatusCode::BAD_REQUEST);
+        }
 
-            // Load image bytes from the proxied response.
-            let image_bytes = match proxy_res.bytes().await {
-                Err(err) => {
-                    tracing::error!(err = %err, "failed to load image bytes");
-                    return Ok(response_with_status(http::StatusCode::BAD_GATEWAY));
-                }
+        // Caches must not serve the image past the expiry of the URL.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let max_age = transformation_params
+            .expires
+            .map_or(MAX_AGE, |expires| expires.saturating_sub(now).min(MAX_AGE));
 
-                Ok(image_bytes) => image_bytes,
-            };
+        let output_formats = match transformation_params.format.unwrap_or_default() {
+            OutputFormat::Format(format) if self.supports(format) => vec![format],
+
+            requested_format => {
+                if let OutputFormat::Format(format) = requested_format {
+                    tracing::warn!(format = ?format, "requested format is not supported");
+                }
 
-            // Apply image transformation in accordance with the request specification.
-            //
-            // Note that this is a blocking action, so we spawn a dedicated blocking task.
-            let transformed_image = match task::spawn_blocking(move || {
-                transform_image(
-                    &accept,
-                    supported_image_types,
-                    &image_bytes,
-                    &transformation_params,
+                negotiate::preferred_formats(
+                    accept,
+                    self.supported_image_types,
+                    self.default_format,
                 )
-            })
+            }
+        };
+
+        if output_formats.is_empty() {
+            tracing::error!(
+                accept = ?accept,
+                supported_media_types = ?self.supported_image_types,
+                "no acceptable image type"
+            );
+            return Err(http::StatusCode::NOT_ACCEPTABLE);
+        }
+
+        let image_bytes = self.fetch(target_url).await?;
+
+        self.transform_bytes(image_bytes, transformation_params, output_formats, max_age)
             .await
-            {
-                // Something went wrong with the task.
-                Err(err) => {
-                    tracing::error!(err = %err, "failed to transform image (task failed)");
-                    return Ok(response_with_status(
-                        http::StatusCode::INTERNAL_SERVER_ERROR,
-                    ));
+    }
+
+    /// Responds to a request of the IIIF Image API.
+    async fn iiif(
+        &self,
+        iiif: &Iiif,
+        iiif_request: IiifRequest,
+    ) -> Result<Response<Full<Bytes>>, http::StatusCode> {
+        match iiif_request {
+            IiifRequest::Redirect { location } => {
+                let location = location.parse().map_err(|_| {
+                    tracing::error!(location, "invalid IIIF redirect location");
+                    http::StatusCode::BAD_REQUEST
+                })?;
+                let mut res = response_with_status(http::StatusCode::SEE_OTHER);
+                res.headers_mut().insert(http::header::LOCATION, location);
+                Ok(res)
+            }
+
+            IiifRequest::Info { id, target } => {
+                let image_bytes = self.fetch(target).await?;
+                let dimensions = source_dimensions(&image_bytes)?;
+                let formats = self
+                    .supported_image_types
+                    .iter()
+                    .map(|supported| supported.image_format);
+
+                Ok(document_response(
+                    iiif.info(&id, dimensions, formats),
+                    "application/json",
+                ))
+            }
+
+            IiifRequest::Image {
+                target,
+                image_request,
+            } => {
+                let format = image_request.format();
+                if !self.supports(format) {
+                    tracing::error!(format = ?format, "requested format is not supported");
+                    return Err(http::StatusCode::BAD_REQUEST);
                 }
 
-                // Something went wrong with the image transformation.
-                Ok(Err(err)) => {
-                    tracing::error!(err = %err, "failed to transform image (transform failed)");
-                    return Ok(response_with_status(
-                        http::StatusCode::INTERNAL_SERVER_ERROR,
-                    ));
+                let image_bytes = self.fetch(target).await?;
+                let dimensions = source_dimensions(&image_bytes)?;
+                let params = image_request.params(dimensions).map_err(|err| {
+                    tracing::error!(err = %err, "invalid IIIF request");
+                    err.status()
+                })?;
+
+                self.transform_bytes(image_bytes, params, vec![format], MAX_AGE)
+                    .await
+            }
+        }
+    }
+
+    /// Responds to a request of a Deep Zoom image.
+    async fn dzi(
+        &self,
+        dzi: &Dzi,
+        dzi_request: DziRequest,
+    ) -> Result<Response<Full<Bytes>>, http::StatusCode> {
+        let (target, level, column, row) = match dzi_request {
+            DziRequest::Descriptor { target } => {
+                // Only the header of the source is read, since viewers request
+                // the descriptor before they request any tiles.
+                let dimensions = match dzi.cached_dimensions(&target) {
+                    Some(dimensions) => dimensions,
+                    None => source_dimensions(&self.fetch(target).await?)?,
+                };
+                return Ok(document_response(
+                    dzi.descriptor(dimensions),
+                    "application/xml",
+                ));
+            }
+
+            DziRequest::Tile {
+                target,
+                level,
+                column,
+                row,
+            } => (target, level, column, row),
+        };
+
+        // The source is only fetched and decoded when no level it could be
+        // downscaled from is cached.
+        let source = dzi
+            .level_or_load(&target, level, || async {
+                let image_bytes = self.fetch(target.clone()).await?;
+                let dzi = dzi.clone();
+                let target = target.clone();
+                let color = self.color;
+
+                // Note that this is a blocking action, so we spawn a dedicated blocking task.
+                let source = task::spawn_blocking(move || {
+                    decode_source(&image_bytes, color)
+                        .map(|image| dzi.insert_source(&target, image))
+                })
+                .await;
+
+                match source {
+                    Err(err) => {
+                        tracing::error!(err = %err, "failed to decode source (task failed)");
+                        Err(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+
+                    Ok(Err(err)) => {
+                        tracing::error!(err = %err, "failed to decode source");
+                        Err(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+
+                    Ok(Ok(source)) => Ok(source),
                 }
+            })
+            .await?;
 
-                Ok(Ok(transformed_image)) => transformed_image,
-            };
+        let dzi = dzi.clone();
+        let format = dzi.format();
+        let encode_options = EncodeOptions {
+            progressive: self.progressive,
+            palette: None,
+            quality: None,
+            byte_budget: None,
+        };
 
-            // Construct response headers.
-            //
-            // We provide `Vary`, to ensure appropriate caching; i.e. based on the value of
-            // `Accept`.
-            //
-            // A `Cache-Control` is hardcoded for now, but should be made configurable in
-            // the future.
-            //
-            // Both `Content-Type` and `Content-Length` are derived from the transformed
-            // image directly.
-            let mut res_headers = HeaderMap::new();
-            res_headers.insert(http::header::VARY, http::header::ACCEPT.into());
-            res_headers.insert(
-                http::header::CACHE_CONTROL,
-                // TODO: This should be made configurable with a default when not explicitly
-                // configured.
-                "public, must-revalidate, max-age=31536000, s-maxage=31536000"
-                    .parse()
-                    .expect("Must parse a header value"),
-            );
-            res_headers.insert(
-                http::header::CONTENT_TYPE,
-                transformed_image
-                    .format
-                    .to_mime_type()
-                    .parse()
-                    .expect("Must parse a header value"),
-            );
-            res_headers.insert(
-                http::header::CONTENT_LENGTH,
-                transformed_image.bytes.len().into(),
-            );
+        // Note that this is a blocking action, so we spawn a dedicated blocking task.
+        let tile = task::spawn_blocking(move || {
+            dzi.tile(&target, source, level, column, row)
+                .map(|tile| encode_to_vec(&tile, format, &Metadata::default(), encode_options))
+                .transpose()
+        })
+        .await;
+
+        match tile {
+            Err(err) => {
+                tracing::error!(err = %err, "failed to compute tile (task failed)");
+                Err(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+
+            Ok(Err(err)) => {
+                tracing::error!(err = %err, "failed to compute tile");
+                Err(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
 
-            let mut res = Response::new(Full::from(Bytes::from(transformed_image.bytes)));
-            *res.headers_mut() = res_headers;
+            Ok(Ok(None)) => Err(http::StatusCode::NOT_FOUND),
 
-            Ok(res)
+            Ok(Ok(Some(bytes))) => Ok(image_response(bytes.into(), format, MAX_AGE)),
+        }
+    }
+
+    /// Returns whether `format` is among the supported image types.
+    fn supports(&self, format: ImageFormat) -> bool {
+        self.supported_image_types
+            .iter()
+            .any(|supported| supported.image_format == format)
+    }
+
+    /// Loads the image from the provided image URL.
+    async fn fetch(&self, target_url: Url) -> Result<Bytes, http::StatusCode> {
+        let proxy_res = self.client.get(target_url).send().await.map_err(|err| {
+            tracing::error!(err = %err, "failed to load image");
+            http::StatusCode::BAD_GATEWAY
+        })?;
+
+        // Load image bytes from the proxied response.
+        proxy_res.bytes().await.map_err(|err| {
+            tracing::error!(err = %err, "failed to load image bytes");
+            http::StatusCode::BAD_GATEWAY
         })
     }
+
+    /// Transforms the image bytes in accordance with the request specification,
+    /// responding with the transformed image.
+    async fn transform_bytes(
+        &self,
+        image_bytes: Bytes,
+        transformation_params: TransformationParams,
+        output_formats: Vec<ImageFormat>,
+        max_age: u64,
+    ) -> Result<Response<Full<Bytes>>, http::StatusCode> {
+        let encode_options = EncodeOptions {
+            progressive: transformation_params
+                .progressive
+                .unwrap_or(self.progressive),
+            palette: transformation_params.colors.map(|colors| PaletteOptions {
+                colors,
+                dither: transformation_params.dither.unwrap_or(false),
+            }),
+            quality: transformation_params.quality,
+            byte_budget: transformation_params.max_bytes.map(|max_bytes| ByteBudget {
+                max_bytes,
+                allow_downscale: self.max_bytes_downscale,
+                time_limit: self.max_bytes_time_limit,
+            }),
+        };
+        let transform_options = TransformOptions {
+            metadata_policy: transformation_params
+                .metadata
+                .unwrap_or(self.metadata_policy),
+            color: self.color,
+            encode: encode_options,
+            passthrough: self.passthrough,
+        };
+
+        // Note that this is a blocking action, so we spawn a dedicated blocking task.
+        let transformed_image = match task::spawn_blocking(move || {
+            transform_image(
+                &image_bytes,
+                &transformation_params,
+                &output_formats,
+                transform_options,
+            )
+        })
+        .await
+        {
+            // Something went wrong with the task.
+            Err(err) => {
+                tracing::error!(err = %err, "failed to transform image (task failed)");
+                return Err(http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+
+            // The image could not be made to fit the requested byte budget.
+            Ok(Err(err @ ImageXformError::ByteBudgetExceeded { .. })) => {
+                tracing::error!(err = %err, "failed to transform image (budget exceeded)");
+                return Err(http::StatusCode::UNPROCESSABLE_ENTITY);
+            }
+
+            // The requested crop region does not overlap the image.
+            Ok(Err(err @ ImageXformError::CropOutOfBounds)) => {
+                tracing::error!(err = %err, "failed to transform image (invalid crop)");
+                return Err(http::StatusCode::BAD_REQUEST);
+            }
+
+            // Something went wrong with the image transformation.
+            Ok(Err(err)) => {
+                tracing::error!(err = %err, "failed to transform image (transform failed)");
+                return Err(http::StatusCode::INTERNAL_SERVER_ERROR);
+            }
+
+            Ok(Ok(transformed_image)) => transformed_image,
+        };
+
+        // We provide `Vary`, to ensure appropriate caching; i.e. based on the value of
+        // `Accept`.
+        let mut res = image_response(transformed_image.bytes, transformed_image.format, max_age);
+        res.headers_mut()
+            .insert(http::header::VARY, http::header::ACCEPT.into());
+
+        Ok(res)
+    }
+}
+
+/// Returns a response with an image.
+fn image_response(bytes: Bytes, format: ImageFormat, max_age: u64) -> Response<Full<Bytes>> {
+    // Construct response headers.
+    //
+    // A `Cache-Control` is hardcoded for now, but should be made configurable in
+    // the future.
+    //
+    // Both `Content-Type` and `Content-Length` are derived from the image directly.
+    let mut res_headers = HeaderMap::new();
+    res_headers.insert(
+        http::header::CACHE_CONTROL,
+        // TODO: This should be made configurable with a default when not explicitly
+        // configured.
+        format!("public, must-revalidate, max-age={max_age}, s-maxage={max_age}")
+            .parse()
+            .expect("Must parse a header value"),
+    );
+    res_headers.insert(
+        http::header::CONTENT_TYPE,
+        format
+            .to_mime_type()
+            .parse()
+            .expect("Must parse a header value"),
+    );
+    res_headers.insert(http::header::CONTENT_LENGTH, bytes.len().into());
+
+    let mut res = Response::new(Full::from(bytes));
+    *res.headers_mut() = res_headers;
+    res
+}
+
+/// Returns the status of the response to a URL which could not be verified.
+///
+/// Tampered URLs are forbidden and expired URLs are gone, whereas malformed
+/// signatures are bad requests.
+fn verify_error_status(err: &VerifyError) -> http::StatusCode {
+    match err {
+        VerifyError::Mismatch | VerifyError::UnknownKeyId(_) | VerifyError::Unsigned => {
+            http::StatusCode::FORBIDDEN
+        }
+        VerifyError::Expired { .. } => http::StatusCode::GONE,
+        _ => http::StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Returns the dimensions of an image, once oriented.
+fn source_dimensions(image_bytes: &Bytes) -> Result<(u32, u32), http::StatusCode> {
+    let dimensions = || -> Result<(u32, u32), ImageXformError> {
+        let mut decoder = ImageReader::new(Cursor::new(image_bytes))
+            .with_guessed_format()
+            .map_err(|err| ImageXformError::Image(image::error::ImageError::IoError(err)))?
+            .into_decoder()?;
+        let (width, height) = decoder.dimensions();
+
+        Ok(match decoder.orientation()? {
+            Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90FlipH
+            | Orientation::Rotate270FlipH => (height, width),
+            _ => (width, height),
+        })
+    };
+
+    dimensions().map_err(|err| {
+        tracing::error!(err = %err, "failed to read image dimensions");
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Decodes the source of a Deep Zoom image, oriented and converted to sRGB.
+///
+/// Sources are decoded without memory limits, since they're configured rather
+/// than requested.
+fn decode_source(
+    image_bytes: &Bytes,
+    color: ColorOptions,
+) -> Result<DynamicImage, ImageXformError> {
+    let mut image_reader = ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()
+        .map_err(|err| ImageXformError::Image(image::error::ImageError::IoError(err)))?;
+    image_reader.no_limits();
+
+    let mut decoder = image_reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let source_profile = if color.convert_to_srgb {
+        decoder.icc_profile()?
+    } else {
+        None
+    };
+
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    Ok(source_profile
+        .as_deref()
+        .and_then(|profile| color::convert_to_srgb(&image, profile))
+        .unwrap_or(image))
+}
+
+/// Returns a response with a document describing an image, such as a IIIF
+/// `info.json` descriptor.
+fn document_response(document: String, content_type: &'static str) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::from(document));
+    let headers = res.headers_mut();
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        format!("public, must-revalidate, max-age={MAX_AGE}, s-maxage={MAX_AGE}")
+            .parse()
+            .expect("Must parse a header value"),
+    );
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static(content_type),
+    );
+    res
+}
+
+/// Allows any origin to read the response.
+fn with_any_origin<B>(mut res: Response<B>) -> Response<B> {
+    res.headers_mut().insert(
+        http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        http::HeaderValue::from_static("*"),
+    );
+    res
 }
 
 fn response_with_status<B>(status_code: http::StatusCode) -> Response<B>
@@ -271,64 +970,712 @@ where
     res
 }
 
-#[instrument(skip_all, fields(accept, supported_image_types, image_xform_req), err)]
-fn transform_image<'a>(
-    accept: &Accept,
-    supported_image_types: &'a [SupportedImageType<'a>],
-    image_bytes: &[u8],
+#[instrument(skip_all, fields(output_formats, image_xform_req), err)]
+fn transform_image(
+    image_bytes: &Bytes,
     transformation_params: &TransformationParams,
+    output_formats: &[ImageFormat],
+    options: TransformOptions,
 ) -> Result<TransformedImage, ImageXformError> {
     let image_reader = ImageReader::new(Cursor::new(image_bytes))
         .with_guessed_format()
         .map_err(|err| ImageXformError::Image(image::error::ImageError::IoError(err)))?;
 
     let guessed_format = image_reader.format();
-    let format = determine_format(accept, supported_image_types, guessed_format);
 
-    let mut image = image_reader.decode().map_err(ImageXformError::Image)?;
+    let mut decoder = image_reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let source_profile = decoder.icc_profile()?;
+
+    let passthrough_format = match options.passthrough {
+        Passthrough::Never => None,
+        Passthrough::Always | Passthrough::Smaller => passthrough_format(
+            guessed_format,
+            transformation_params,
+            output_formats,
+            &options,
+            &mut decoder,
+            orientation,
+            image_bytes.len(),
+        )?,
+    };
 
-    if transformation_params.width.is_some() || transformation_params.height.is_some() {
-        let width = transformation_params.width.unwrap_or_else(|| image.width());
-        let height = transformation_params
-            .height
-            .unwrap_or_else(|| image.height());
-        image = image.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+    if let (Passthrough::Always, Some(format)) = (options.passthrough, passthrough_format) {
+        return Ok(TransformedImage {
+            bytes: image_bytes.clone(),
+            format,
+        });
     }
 
-    let mut writer = BufWriter::new(Cursor::new(Vec::with_capacity(image.as_bytes().len())));
-    image.write_to(&mut writer, format)?;
+    let mut metadata = Metadata::read(&mut decoder, options.metadata_policy)?;
 
-    Ok(TransformedImage {
-        bytes: writer
-            .into_inner()
-            .map_err(ImageXformError::WriterFinalization)?
-            .into_inner(),
-        format,
-    })
+    let mut image = DynamicImage::from_decoder(decoder).map_err(ImageXformError::Image)?;
+
+    // Orientation is applied to the pixels directly, since the EXIF tag which
+    // describes it is not carried over.
+    image.apply_orientation(orientation);
+
+    // Untagged pixels are taken to be sRGB already. Pixels are converted
+    // before any other operation, so that it's applied to sRGB values.
+    let mut is_srgb = source_profile.is_none();
+
+    if let Some(converted) = source_profile
+        .as_deref()
+        .filter(|_| options.color.convert_to_srgb)
+        .and_then(|profile| color::convert_to_srgb(&image, profile))
+    {
+        image = converted;
+        is_srgb = true;
+
+        // The source profile no longer describes the pixels, so it's replaced by
+        // the sRGB profile wherever it would have been kept.
+        if metadata.icc_profile.is_some() {
+            metadata.icc_profile = color::srgb_profile();
+        }
+    }
+
+    if let Some(crop) = transformation_params.crop {
+        image = geometry::crop(image, crop).ok_or(ImageXformError::CropOutOfBounds)?;
+    }
+
+    image = resize::resize(
+        image,
+        transformation_params.width,
+        transformation_params.height,
+        transformation_params.fit.unwrap_or_default(),
+    );
+
+    if let Some(flip) = transformation_params.flip {
+        image = geometry::flip(image, flip);
+    }
+
+    if let Some(rotate) = transformation_params.rotate {
+        image = geometry::rotate(image, rotate);
+    }
+
+    if transformation_params.gray == Some(true) && image.color().has_color() {
+        image = image.grayscale();
+
+        // Grey pixels can't be described by the RGB profile of a colour image.
+        metadata.icc_profile = None;
+    }
+
+    // Pixels which remain in another colour space must not be labelled sRGB.
+    if options.color.embed_srgb_profile
+        && is_srgb
+        && image.color().has_color()
+        && metadata.icc_profile.is_none()
+    {
+        metadata.icc_profile = color::srgb_profile();
+    }
+
+    let wants_quality = options.encode.quality.is_some() || options.encode.byte_budget.is_some();
+    let format = determine_format(output_formats, &image, wants_quality);
+
+    let bytes = match (
+        encode_to_vec(&image, format, &metadata, options.encode),
+        passthrough_format,
+    ) {
+        // The upstream image is known to fit the budget, so it's served instead.
+        (Err(ImageXformError::ByteBudgetExceeded { .. }), Some(passthrough_format)) => {
+            return Ok(TransformedImage {
+                bytes: image_bytes.clone(),
+                format: passthrough_format,
+            });
+        }
+
+        (bytes, _) => bytes?,
+    };
+
+    match passthrough_format {
+        Some(passthrough_format) if image_bytes.len() <= bytes.len() => Ok(TransformedImage {
+            bytes: image_bytes.clone(),
+            format: passthrough_format,
+        }),
+
+        _ => Ok(TransformedImage {
+            bytes: bytes.into(),
+            format,
+        }),
+    }
 }
 
-#[instrument(skip_all, fields(accept, supported_image_types, guessed_format), ret)]
-fn determine_format<'a>(
-    accept: &Accept,
-    supported_image_types: &'a [SupportedImageType<'a>],
+/// Returns the format of the upstream image, whose encoded bytes are
+/// `encoded_len` long, if it's eligible to be served as-is.
+fn passthrough_format(
     guessed_format: Option<ImageFormat>,
+    transformation_params: &TransformationParams,
+    output_formats: &[ImageFormat],
+    options: &TransformOptions,
+    decoder: &mut impl ImageDecoder,
+    orientation: Orientation,
+    encoded_len: usize,
+) -> Result<Option<ImageFormat>, ImageXformError> {
+    let Some(format) = guessed_format.filter(|format| output_formats.contains(format)) else {
+        return Ok(None);
+    };
+
+    // Any of these would alter the pixels of the upstream image.
+    let dimensions = decoder.dimensions();
+    let unaltered = resize::resized_dimensions(
+        dimensions,
+        transformation_params.width,
+        transformation_params.height,
+        transformation_params.fit.unwrap_or_default(),
+    ) == dimensions
+        && orientation == Orientation::NoTransforms
+        && transformation_params.crop.is_none()
+        && transformation_params.rotate.is_none()
+        && transformation_params.flip.is_none()
+        && transformation_params.gray != Some(true)
+        && transformation_params.quality.is_none()
+        && options.encode.palette.is_none()
+        && !options.encode.progressive;
+
+    let within_budget = options
+        .encode
+        .byte_budget
+        .is_none_or(|budget| encoded_len as u64 <= budget.max_bytes);
+
+    if !(unaltered && within_budget) {
+        return Ok(None);
+    }
+
+    // Metadata which would otherwise be stripped, or a colour profile which
+    // would otherwise be converted or replaced, must not be passed through.
+    let has_icc_profile = decoder.icc_profile()?.is_some();
+    let keeps_icc_profile = options.metadata_policy.keeps_icc()
+        && !options.color.convert_to_srgb
+        && !options.color.embed_srgb_profile;
+    let has_metadata = decoder.exif_metadata()?.is_some() || decoder.xmp_metadata()?.is_some();
+
+    if has_metadata || (has_icc_profile && !keeps_icc_profile) || options.color.embed_srgb_profile {
+        return Ok(None);
+    }
+
+    Ok(Some(format))
+}
+
+/// Chooses among the formats the client prefers most.
+///
+/// When several are preferred equally, the content of the image decides between
+/// lossy and lossless formats, with the earlier formats preferred among those
+/// which suit it. Lossy formats are preferred regardless of content when
+/// `wants_quality` is set, as only their encoders honour a quality or byte
+/// budget.
+#[instrument(skip_all, fields(output_formats))]
+fn determine_format(
+    output_formats: &[ImageFormat],
+    image: &DynamicImage,
+    wants_quality: bool,
 ) -> ImageFormat {
-    let supported_media_types = supported_image_types.iter().map(Into::into);
+    let preferred = output_formats[0];
 
-    if let Some(negotiated) = accept.negotiate(supported_media_types) {
-        for supported in supported_image_types {
-            if supported.media_type == *negotiated {
-                return supported.image_format;
+    if output_formats.len() < 2 {
+        return preferred;
+    }
+
+    let analysis = content::analyze(image);
+    let is_photo = analysis.content == ImageContent::Photo;
+    let suits = |lossy: bool| {
+        move |format: &&ImageFormat| {
+            is_lossy(**format) == lossy && (!analysis.has_alpha || supports_alpha(**format))
+        }
+    };
+
+    output_formats
+        .iter()
+        .find(suits(is_photo || wants_quality))
+        .or_else(|| output_formats.iter().find(suits(is_photo)))
+        .copied()
+        .unwrap_or(preferred)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use image::{
+        codecs::png::{CompressionType, FilterType as PngFilterType, PngDecoder, PngEncoder},
+        ImageEncoder, Rgb, RgbImage, RgbaImage,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{signed::UrlSigner, KeyRing, ParamsError, TargetEncoding};
+
+    /// Serves `body` to every request, returning the URL of an image on the
+    /// server.
+    async fn serve(body: Vec<u8>) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let _ = stream.read(&mut [0; 4096]).await;
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
             }
+        });
+        format!("http://{addr}/image.png").parse().unwrap()
+    }
+
+    fn png() -> Vec<u8> {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([0, 128, 255])))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    /// Requests the path and query of `url` from `transformer`.
+    async fn get(
+        mut transformer: ImageTransformer,
+        url: &Url,
+        accept: Option<&str>,
+    ) -> Response<Full<Bytes>> {
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        };
+        let mut req = Request::builder().uri(path_and_query);
+        if let Some(accept) = accept {
+            req = req.header(header::ACCEPT, accept);
         }
+        transformer.call(req.body(()).unwrap()).await.unwrap()
     }
 
-    tracing::warn!(
-        accept = %accept,
-        supported_media_types = ?supported_image_types,
-        "No supported image type found"
-    );
+    fn content_type(res: &Response<Full<Bytes>>) -> Option<&str> {
+        res.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    #[tokio::test]
+    async fn negotiates_configured_formats() {
+        let key = Key::generate();
+        let target = serve(png()).await;
+        let signed = UrlSigner::new(key.clone(), "http://localhost/".parse().unwrap())
+            .sign(&TransformationParams::default(), &target)
+            .unwrap();
+        let transformer = || {
+            ImageTransformerBuilder::new(key.clone())
+                .set_default_format(ImageFormat::Png)
+                .build()
+        };
+
+        for (accept, expected) in [
+            (None, "image/png"),
+            (Some("*/*"), "image/png"),
+            (Some("image/webp"), "image/webp"),
+            (Some("image/png;q=0.9, image/webp;q=0.5"), "image/png"),
+        ] {
+            let res = get(transformer(), &signed, accept).await;
+            assert_eq!(res.status(), http::StatusCode::OK, "{accept:?}");
+            assert_eq!(content_type(&res), Some(expected), "{accept:?}");
+        }
+
+        // The format of the upstream image isn't served unless it's acceptable.
+        for accept in ["image/jpeg", "image/png;q=0, image/webp;q=0", "text/html"] {
+            let res = get(transformer(), &signed, Some(accept)).await;
+            assert_eq!(res.status(), http::StatusCode::NOT_ACCEPTABLE, "{accept}");
+        }
+    }
+
+    fn max_age(res: &Response<Full<Bytes>>) -> Option<u64> {
+        res.headers()
+            .get(header::CACHE_CONTROL)?
+            .to_str()
+            .ok()?
+            .split(", ")
+            .find_map(|directive| directive.strip_prefix("max-age="))?
+            .parse()
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn honours_expiry() {
+        let key = Key::generate();
+        let target = serve(png()).await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signed = |expires| {
+            let params = TransformationParams {
+                expires: Some(expires),
+                ..Default::default()
+            };
+            UrlSigner::new(key.clone(), "http://localhost/".parse().unwrap())
+                .sign(&params, &target)
+                .unwrap()
+        };
+        let transformer = |clock_skew| {
+            ImageTransformerBuilder::new(key.clone())
+                .set_clock_skew(Duration::from_secs(clock_skew))
+                .build()
+        };
+
+        // Caches must not serve the image past the expiry.
+        let res = get(transformer(60), &signed(now + 3600), None).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert!((3500..=3600).contains(&max_age(&res).unwrap()));
+
+        // URLs are accepted within the clock skew past their expiry.
+        let res = get(transformer(60), &signed(now - 10), None).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert_eq!(max_age(&res), Some(0));
+
+        for (clock_skew, expires) in [(60, now - 3600), (0, now - 10)] {
+            let res = get(transformer(clock_skew), &signed(expires), None).await;
+            assert_eq!(res.status(), http::StatusCode::GONE);
+        }
+
+        // URLs without an expiry may be cached for long.
+        let unexpiring = UrlSigner::new(key.clone(), "http://localhost/".parse().unwrap())
+            .sign(&TransformationParams::default(), &target)
+            .unwrap();
+        let res = get(transformer(60), &unexpiring, None).await;
+        assert_eq!(max_age(&res), Some(MAX_AGE));
+    }
+
+    #[test]
+    fn maps_verify_errors_to_statuses() {
+        for (err, status) in [
+            (
+                VerifyError::MalformedEncoding,
+                http::StatusCode::BAD_REQUEST,
+            ),
+            (
+                VerifyError::WrongLength {
+                    expected: 32,
+                    actual: 3,
+                },
+                http::StatusCode::BAD_REQUEST,
+            ),
+            (VerifyError::Mismatch, http::StatusCode::FORBIDDEN),
+            (VerifyError::Expired { expires: 0 }, http::StatusCode::GONE),
+            (
+                VerifyError::UnknownKeyId("old".to_owned()),
+                http::StatusCode::FORBIDDEN,
+            ),
+            (VerifyError::MalformedUrl, http::StatusCode::BAD_REQUEST),
+            (
+                VerifyError::InvalidParams(ParamsError::UnknownKey("x".to_owned())),
+                http::StatusCode::BAD_REQUEST,
+            ),
+            (VerifyError::InvalidTarget, http::StatusCode::BAD_REQUEST),
+            (VerifyError::Unsigned, http::StatusCode::FORBIDDEN),
+        ] {
+            assert_eq!(verify_error_status(&err), status, "{err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unverified_urls() {
+        let key = Key::generate();
+        let base: Url = "http://localhost/".parse().unwrap();
+        let target: Url = "http://localhost/image.png".parse().unwrap();
+        let params = TransformationParams {
+            expires: Some(1),
+            ..Default::default()
+        };
+        let encrypted = |key: KeyRing| {
+            UrlSigner::new(key, base.clone())
+                .set_target_encoding(TargetEncoding::Encrypted)
+                .sign(&TransformationParams::default(), &target)
+                .unwrap()
+        };
+        let transformer = ImageTransformerBuilder::new(
+            KeyRing::new(key.clone())
+                .set_key_id("current")
+                .add_verification_key_with_id("previous", Key::generate()),
+        )
+        .build();
+
+        let unknown_key_id = encrypted(KeyRing::new(Key::generate()).set_key_id("revoked"));
+        let other_key = encrypted(KeyRing::new(Key::generate()).set_key_id("current"));
+        let mut tampered = encrypted(KeyRing::new(key.clone()).set_key_id("current"));
+        let ciphertext = tampered
+            .path_segments()
+            .unwrap()
+            .next_back()
+            .unwrap()
+            .to_owned();
+        let flipped = if ciphertext.starts_with('A') {
+            "B"
+        } else {
+            "A"
+        };
+        tampered.set_path(
+            &tampered
+                .path()
+                .replace(&ciphertext, &format!("{flipped}{}", &ciphertext[1..])),
+        );
+        let expired = UrlSigner::new(KeyRing::new(key).set_key_id("current"), base.clone())
+            .sign(&params, &target)
+            .unwrap();
 
-    // Default to PNG if no media type is negotiated
-    guessed_format.unwrap_or(ImageFormat::Png)
+        for (url, status) in [
+            (unknown_key_id, http::StatusCode::FORBIDDEN),
+            (other_key, http::StatusCode::FORBIDDEN),
+            (tampered, http::StatusCode::FORBIDDEN),
+            (expired, http::StatusCode::GONE),
+            (
+                base.join("current.AAAA/w_100/enc/AAAA").unwrap(),
+                http::StatusCode::BAD_REQUEST,
+            ),
+            (base.join("w_100").unwrap(), http::StatusCode::BAD_REQUEST),
+        ] {
+            let res = get(transformer.clone(), &url, None).await;
+            assert_eq!(res.status(), status, "{url}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "default format must be among the supported image types")]
+    fn rejects_unsupported_default_formats() {
+        ImageTransformerBuilder::new(Key::generate())
+            .set_default_format(ImageFormat::Bmp)
+            .build();
+    }
+
+    #[test]
+    #[should_panic(expected = "Deep Zoom tile format must be among the supported image types")]
+    fn rejects_unsupported_tile_formats() {
+        let dzi =
+            Dzi::new("https://example.com/images/".parse().unwrap()).set_format(ImageFormat::Bmp);
+        ImageTransformerBuilder::new(Key::generate())
+            .set_protocol(Protocol::Dzi(dzi))
+            .build();
+    }
+
+    #[tokio::test]
+    async fn describes_deep_zoom_images_without_decoding_them() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(300, 200))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        // The headers, up to the start of the pixel data.
+        let mut header = png.into_inner();
+        let pixels = header
+            .windows(4)
+            .position(|window| window == b"IDAT")
+            .unwrap();
+        header.truncate(pixels + 8);
+
+        let source = serve(header).await.join("./").unwrap();
+        let transformer = ImageTransformerBuilder::new(Key::generate())
+            .set_protocol(Protocol::Dzi(Dzi::new(source).set_format(ImageFormat::Png)))
+            .build();
+        let base = Url::parse("http://localhost/").unwrap();
+
+        let res = get(transformer.clone(), &base.join("a.png.dzi").unwrap(), None).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        let descriptor = res.into_body().collect().await.unwrap().to_bytes();
+        assert!(std::str::from_utf8(&descriptor)
+            .unwrap()
+            .contains(r#"<Size Width="300" Height="200"/>"#));
+
+        assert_eq!(
+            get(
+                transformer,
+                &base.join("a.png_files/0/0_0.png").unwrap(),
+                None
+            )
+            .await
+            .status(),
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unsafe Thumbor URLs must be restricted to a source")]
+    fn rejects_unsafe_thumbor_urls_without_a_source() {
+        ImageTransformerBuilder::new(Key::generate())
+            .set_protocol(Protocol::Thumbor(
+                Thumbor::new("key").set_allow_unsafe(true),
+            ))
+            .build();
+    }
+
+    fn transform_options() -> TransformOptions {
+        TransformOptions {
+            metadata_policy: MetadataPolicy::Strip,
+            color: ColorOptions {
+                convert_to_srgb: true,
+                embed_srgb_profile: false,
+            },
+            encode: EncodeOptions::default(),
+            passthrough: Passthrough::Never,
+        }
+    }
+
+    #[test]
+    fn converts_to_srgb_before_graying() {
+        let display_p3 = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+        let source = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([0, 255, 0])));
+        let mut png = Vec::new();
+        let mut encoder = PngEncoder::new(&mut png);
+        encoder.set_icc_profile(display_p3.clone()).unwrap();
+        source.write_with_encoder(encoder).unwrap();
+
+        let params = TransformationParams {
+            gray: Some(true),
+            ..Default::default()
+        };
+        let transformed = transform_image(
+            &png.into(),
+            &params,
+            &[ImageFormat::Png],
+            transform_options(),
+        )
+        .unwrap();
+
+        let mut decoder = PngDecoder::new(Cursor::new(transformed.bytes)).unwrap();
+        assert_eq!(decoder.icc_profile().unwrap(), None);
+        let output = DynamicImage::from_decoder(decoder).unwrap();
+        let expected = color::convert_to_srgb(&source, &display_p3)
+            .unwrap()
+            .grayscale();
+        assert!(matches!(output, DynamicImage::ImageLuma8(_)));
+        assert_eq!(output.as_bytes(), expected.as_bytes());
+    }
+
+    #[test]
+    fn passes_through_when_re_encoding_exceeds_the_budget() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        let mut png = Vec::new();
+        image
+            .write_with_encoder(PngEncoder::new_with_quality(
+                &mut png,
+                CompressionType::Best,
+                PngFilterType::Adaptive,
+            ))
+            .unwrap();
+        let png = Bytes::from(png);
+
+        let transform = |passthrough| {
+            let mut options = transform_options();
+            options.passthrough = passthrough;
+            options.encode.byte_budget = Some(ByteBudget {
+                max_bytes: png.len() as u64,
+                allow_downscale: false,
+                time_limit: Duration::from_secs(1),
+            });
+            transform_image(
+                &png,
+                &TransformationParams::default(),
+                &[ImageFormat::Png],
+                options,
+            )
+        };
+
+        // The default compression doesn't get within the budget.
+        assert!(matches!(
+            transform(Passthrough::Never),
+            Err(ImageXformError::ByteBudgetExceeded { .. })
+        ));
+        let transformed = transform(Passthrough::Smaller).unwrap();
+        assert_eq!(transformed.bytes, png);
+        assert_eq!(transformed.format, ImageFormat::Png);
+    }
+
+    #[test]
+    fn chooses_formats_by_content() {
+        let formats = [ImageFormat::WebP, ImageFormat::Jpeg, ImageFormat::Png];
+
+        assert_eq!(
+            determine_format(&formats, &content::tests::photo(), false),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(
+            determine_format(&formats, &content::tests::line_art(), false),
+            ImageFormat::WebP
+        );
+
+        // Transparent photos need a format which can hold transparency.
+        let mut transparent = content::tests::photo().to_rgba8();
+        for pixel in transparent.pixels_mut() {
+            pixel[3] = 128;
+        }
+        let transparent = DynamicImage::ImageRgba8(transparent);
+        assert_eq!(
+            determine_format(&[ImageFormat::Jpeg, ImageFormat::Avif], &transparent, false),
+            ImageFormat::Avif
+        );
+
+        // A single format is used regardless of content.
+        assert_eq!(
+            determine_format(&[ImageFormat::Png], &content::tests::photo(), false),
+            ImageFormat::Png
+        );
+    }
+
+    #[test]
+    fn prefers_lossy_formats_for_quality() {
+        let formats = [ImageFormat::WebP, ImageFormat::Png, ImageFormat::Jpeg];
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([0, 128, 255])));
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(16, 16));
+
+        assert_eq!(determine_format(&formats, &flat, false), ImageFormat::WebP);
+        assert_eq!(determine_format(&formats, &flat, true), ImageFormat::Jpeg);
+        // JPEG can't hold transparency, so the content decides instead.
+        assert_eq!(
+            determine_format(&formats, &transparent, true),
+            ImageFormat::WebP
+        );
+    }
+
+    #[test]
+    fn budgets_passthrough_by_encoded_size() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(RgbaImage::new(64, 64))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        let passthrough = |max_bytes: u64| {
+            let options = TransformOptions {
+                metadata_policy: MetadataPolicy::Strip,
+                color: ColorOptions {
+                    convert_to_srgb: false,
+                    embed_srgb_profile: false,
+                },
+                encode: EncodeOptions {
+                    byte_budget: Some(ByteBudget {
+                        max_bytes,
+                        allow_downscale: false,
+                        time_limit: Duration::from_secs(1),
+                    }),
+                    ..Default::default()
+                },
+                passthrough: Passthrough::Smaller,
+            };
+            let mut decoder = PngDecoder::new(Cursor::new(&png)).unwrap();
+
+            passthrough_format(
+                Some(ImageFormat::Png),
+                &TransformationParams::default(),
+                &[ImageFormat::Png],
+                &options,
+                &mut decoder,
+                Orientation::NoTransforms,
+                png.len(),
+            )
+            .unwrap()
+        };
+
+        // The decoded pixels take far more than the encoded image.
+        assert!(png.len() < 1024 && 64 * 64 * 4 > 1024);
+        assert_eq!(passthrough(1024), Some(ImageFormat::Png));
+        assert_eq!(passthrough(png.len() as u64 - 1), None);
+    }
 }
@@ -1,27 +1,160 @@
-use base64::{engine::general_purpose::URL_SAFE, Engine};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{
+    engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD},
+    Engine,
+};
 use hmac::{Hmac, Mac};
+use image::ImageFormat;
+use percent_encoding::percent_decode_str;
 use sha2::Sha256;
-use url::Url;
+use url::{form_urlencoded, Url};
 
 use crate::{
-    transformation_params::{Height, TransformationParams, Width},
-    Key,
+    cipher::TargetCipher,
+    geometry::{self, Crop, Flip},
+    image_type::OutputFormat,
+    metadata::MetadataPolicy,
+    resize::Fit,
+    transformation_params::{
+        is_valid_preset_name, Height, ParamsError, TransformationParams, Width,
+    },
+    unsigned::{UnsignedPolicy, UNSIGNED},
+    Key, KeyRing,
 };
 
+/// Length of an HMAC-SHA256 digest, in bytes.
+const DIGEST_LENGTH: usize = 32;
+
+/// Default allowance for clocks which run behind the clock a URL with an expiry
+/// was signed with.
+const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Reason a signed URL could not be verified.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum VerifyError {
+    /// The signature is not valid base64.
+    #[error("signature is not valid base64")]
+    MalformedEncoding,
+
+    /// The signature does not have the length of a digest.
+    #[error("signature has {actual} bytes, expected {expected}")]
+    WrongLength {
+        /// Length of a digest.
+        expected: usize,
+
+        /// Length of the signature.
+        actual: usize,
+    },
+
+    /// The signature does not match the value, e.g. because either was
+    /// tampered with or signed with another key.
+    #[error("signature does not match")]
+    Mismatch,
+
+    /// The URL has expired.
+    #[error("signed URL expired at {expires} (seconds since the Unix epoch)")]
+    Expired {
+        /// Expiry of the URL, in seconds since the Unix epoch.
+        expires: u64,
+    },
+
+    /// The signature is prefixed by a key ID which is not in the key ring.
+    #[error("unknown key ID `{0}`")]
+    UnknownKeyId(String),
+
+    /// The URL does not have the layout of a signed URL, or its target URL
+    /// could not be decoded or decrypted.
+    #[error("URL does not have the layout of a signed URL")]
+    MalformedUrl,
+
+    /// The signed transform parameters are invalid.
+    #[error("invalid transform parameters: {0}")]
+    InvalidParams(#[from] ParamsError),
+
+    /// The signed target URL is invalid.
+    #[error("invalid target URL")]
+    InvalidTarget,
+
+    /// The URL is unsigned, and either unsigned URLs are not accepted or it is
+    /// not allowed by the [`UnsignedPolicy`].
+    #[error("unsigned URL is not allowed")]
+    Unsigned,
+}
+
+/// Contents of a signed URL which was verified.
+#[derive(Debug, Clone)]
+pub struct VerifiedUrl {
+    /// Transform parameters.
+    pub params: TransformationParams,
+
+    /// URL of the image to transform.
+    pub target: Url,
+}
+
 /// Verifier of signatures.
 #[derive(Debug, Clone)]
 pub struct Verifier {
-    key: Key,
+    key_ring: KeyRing,
+    cipher: Option<TargetCipher>,
+    clock_skew: Duration,
+    unsigned_policy: Option<UnsignedPolicy>,
 }
 
 impl Verifier {
-    /// Create a new [`Verifier`] with the provided [`Key`].
-    pub const fn new(key: Key) -> Self {
-        Self { key }
+    /// Create a new [`Verifier`] with the provided [`Key`] or [`KeyRing`].
+    ///
+    /// Encrypted target URLs are decrypted with the key which verifies their
+    /// signature, as picked by its key ID or else by trying every key, unless
+    /// another key is set via [`Verifier::set_encryption_key`].
+    pub fn new(key: impl Into<KeyRing>) -> Self {
+        Self {
+            cipher: None,
+            key_ring: key.into(),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+            unsigned_policy: None,
+        }
+    }
+
+    /// Set the key which encrypted target URLs are decrypted with.
+    ///
+    /// This must match the key given to [`SignedUrlBuilder::encryption_key`].
+    pub fn set_encryption_key(self, encryption_key: Key) -> Self {
+        Self {
+            cipher: Some(TargetCipher::new(&encryption_key)),
+            ..self
+        }
+    }
+
+    /// Set how long past their expiry URLs are still accepted, to allow for
+    /// clocks which run behind the clock URLs are signed with.
+    ///
+    /// Defaults to one minute.
+    pub fn set_clock_skew(self, clock_skew: Duration) -> Self {
+        Self { clock_skew, ..self }
+    }
+
+    /// Set the policy for accepting unsigned URLs.
+    ///
+    /// By default, unsigned URLs are not accepted.
+    pub fn set_unsigned_policy(self, unsigned_policy: UnsignedPolicy) -> Self {
+        Self {
+            unsigned_policy: Some(unsigned_policy),
+            ..self
+        }
     }
 
     /// Verify a given signature and value.
     ///
+    /// A signature prefixed by a key ID, as in `{key_id}.{signature}`, is only
+    /// verified with the key of that ID. Otherwise, every key of the key ring
+    /// is tried.
+    ///
     /// # Example
     ///
     /// ```rust
@@ -42,58 +175,673 @@ impl Verifier {
     /// # let sig = "ZkGOa8OrigopaLapeyNwVkREmYauORdo9OYh3-2rvQY=";
     /// # let val = "foobar";
     ///
-    /// assert!(verifier.verify(sig, val));
+    /// assert!(verifier.verify(sig, val).is_ok());
     /// ```
-    pub fn verify(&self, signature: &str, value: &str) -> bool {
-        let Ok(digest) = URL_SAFE.decode(signature) else {
-            tracing::warn!("could not Base64 decode signature");
-            return false;
+    pub fn verify(&self, signature: &str, value: &str) -> Result<(), VerifyError> {
+        let (key_id, signature) = match signature.split_once('.') {
+            Some((key_id, signature)) => (Some(key_id), signature),
+            None => (None, signature),
         };
 
-        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_slice())
-            .expect("HMAC can take key of any size");
-        mac.update(value.as_bytes());
-        mac.verify_slice(&digest).is_ok()
+        let digest = URL_SAFE
+            .decode(signature)
+            .map_err(|_| VerifyError::MalformedEncoding)?;
+
+        if digest.len() != DIGEST_LENGTH {
+            return Err(VerifyError::WrongLength {
+                expected: DIGEST_LENGTH,
+                actual: digest.len(),
+            });
+        }
+
+        let mut keys = self.key_ring.verification_keys(key_id).peekable();
+
+        if let (Some(key_id), None) = (key_id, keys.peek()) {
+            return Err(VerifyError::UnknownKeyId(key_id.to_owned()));
+        }
+
+        keys.any(|key| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_slice())
+                .expect("HMAC can take key of any size");
+            mac.update(value.as_bytes());
+            mac.verify_slice(&digest).is_ok()
+        })
+        .then_some(())
+        .ok_or(VerifyError::Mismatch)
+    }
+
+    /// Verify that an expiry, in seconds since the Unix epoch, has not passed
+    /// by more than the allowed clock skew.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use tower_image_xform::{Key, Verifier, VerifyError};
+    ///
+    /// let verifier = Verifier::new(Key::generate());
+    ///
+    /// assert!(verifier.verify_expiry(u64::MAX).is_ok());
+    /// assert_eq!(
+    ///     verifier.verify_expiry(0),
+    ///     Err(VerifyError::Expired { expires: 0 })
+    /// );
+    /// ```
+    pub fn verify_expiry(&self, expires: u64) -> Result<(), VerifyError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+
+        if now > expires.saturating_add(self.clock_skew.as_secs()) {
+            return Err(VerifyError::Expired { expires });
+        }
+
+        Ok(())
+    }
+
+    /// Verify a signed URL, returning its transform parameters and target URL.
+    ///
+    /// The URL may be given in full or as a path, with or without the path of
+    /// its base URL, in either layout produced by [`SignedUrl`]. Its expiry,
+    /// if any, is verified as well.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use tower_image_xform::{Key, SignedUrlBuilder, Verifier};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let key = Key::generate();
+    /// let signed_url = SignedUrlBuilder::new()
+    ///     .key(key.clone())
+    ///     .base("https://example.com/_image/".parse()?)
+    ///     .params()
+    ///     .width(100)
+    ///     .target("https://example.com/image.png".parse()?)
+    ///     .build()
+    ///     .generate_signed_url()?;
+    ///
+    /// let verified_url = Verifier::new(key).verify_url(signed_url.as_str())?;
+    /// assert_eq!(verified_url.params.width, Some(100));
+    /// assert_eq!(
+    ///     verified_url.target.as_str(),
+    ///     "https://example.com/image.png"
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn verify_url(&self, url: &str) -> Result<VerifiedUrl, VerifyError> {
+        let parsed = Url::parse(url).ok();
+        let (path, query) = match &parsed {
+            Some(parsed) => (parsed.path(), parsed.query()),
+            None => match url.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (url, None),
+            },
+        };
+
+        // The signed segments are the last of the path; an encrypted target URL
+        // spans two segments.
+        let segments = if path.rsplit('/').nth(1) == Some("enc") {
+            4
+        } else {
+            3
+        };
+        let path = path
+            .rmatch_indices('/')
+            .nth(segments - 1)
+            .map_or(path, |(index, _)| &path[index + 1..]);
+
+        self.verify_path_and_query(path, query)
+    }
+
+    /// Verify a signed URL, given the path relative to where the service is
+    /// mounted and the query.
+    pub(crate) fn verify_path_and_query(
+        &self,
+        path: &str,
+        query: Option<&str>,
+    ) -> Result<VerifiedUrl, VerifyError> {
+        let signed_parts = SignedParts::from_path_and_query(path, query)?;
+
+        let unsigned = signed_parts.signature == UNSIGNED;
+        if unsigned && self.unsigned_policy.is_none() {
+            return Err(VerifyError::Unsigned);
+        }
+
+        if !unsigned {
+            let value = match &signed_parts.target {
+                SignedTarget::Encoded(target) => [&*signed_parts.params, target].concat(),
+                SignedTarget::Encrypted(encrypted) => {
+                    [&*signed_parts.params, "enc/", encrypted].concat()
+                }
+            };
+            self.verify(&signed_parts.signature, &value)?;
+        }
+
+        let params: TransformationParams = signed_parts.params.parse()?;
+
+        if let Some(expires) = params.expires {
+            self.verify_expiry(expires)?;
+        }
+
+        // Encrypted target URLs are only decrypted once their signature is
+        // verified.
+        let target = match &signed_parts.target {
+            SignedTarget::Encoded(target) => percent_decode_str(target)
+                .decode_utf8()
+                .ok()
+                .map(Cow::into_owned),
+            SignedTarget::Encrypted(encrypted) => Some(
+                self.decrypt(&signed_parts.signature, encrypted)
+                    .ok_or(VerifyError::MalformedUrl)?,
+            ),
+        }
+        .and_then(|decoded| decoded.parse::<Url>().ok())
+        .ok_or(VerifyError::InvalidTarget)?;
+
+        if let (true, Some(unsigned_policy)) = (unsigned, &self.unsigned_policy) {
+            if !unsigned_policy.allows(&params, &target) {
+                return Err(VerifyError::Unsigned);
+            }
+        }
+
+        Ok(VerifiedUrl { params, target })
+    }
+
+    /// Decrypts the target URL of a signed URL with the given signature.
+    fn decrypt(&self, signature: &str, encrypted: &str) -> Option<String> {
+        let target = match &self.cipher {
+            Some(cipher) => cipher.decrypt(encrypted),
+            None => {
+                let key_id = signature.split_once('.').map(|(key_id, _)| key_id);
+                self.key_ring
+                    .verification_keys(key_id)
+                    .find_map(|key| TargetCipher::new(key).decrypt(encrypted))
+            }
+        };
+
+        if target.is_none() {
+            tracing::warn!("could not decrypt target URL");
+        }
+
+        target
     }
 }
 
+/// Signature, transform parameters, and target URL of a request, in the form
+/// they were signed.
+pub(crate) struct SignedParts<'a> {
+    signature: Cow<'a, str>,
+    params: Cow<'a, str>,
+    target: SignedTarget<'a>,
+}
+
+/// Target URL of a request, in the form it was signed.
+enum SignedTarget<'a> {
+    /// URL-encoded target URL.
+    Encoded(Cow<'a, str>),
+
+    /// Encrypted target URL, which is signed as `enc/{ciphertext}`.
+    Encrypted(Cow<'a, str>),
+}
+
+impl<'a> SignedParts<'a> {
+    /// Extracts the signed parts from either URL layout, preferring the query
+    /// layout when a signature is present in the query.
+    ///
+    /// The path must be relative to where the service is mounted.
+    fn from_path_and_query(path: &'a str, query: Option<&'a str>) -> Result<Self, VerifyError> {
+        match query {
+            Some(query) if form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == "s") => {
+                Self::from_query(query)
+            }
+
+            _ => Self::from_path(path),
+        }
+    }
+
+    // Expected format should follow:
+    //
+    //   /{signature}/{transform_param1},...,{transform_paramN}/{image_url}
+    //
+    // This provides three segments:
+    //
+    //   1. HMAC digest (i.e. signature) of the transform parameters and image URL,
+    //   2. Comma-separated transform parameters,
+    //   3. And URL-encoded image URL.
+    //
+    // For example, a valid request might look like:
+    //
+    //   https://example.com/_image/36c6...5xE=/w_100,h_100/https%3A%2F%2Fwww.rustacean.net%2Fassets%2Frustacean-orig-noshadow.png
+    //
+    // Alternatively, the image URL may be unpadded base64url, optionally followed
+    // by an extension which sets the output format. As the parameters are then
+    // incomplete, the signature covers the canonical parameters instead:
+    //
+    //   https://example.com/_image/36c6...5xE=/w_100,h_100/aHR0cHM6Ly93d3cucnVzdGFjZWFuLm5ldC9hc3NldHMvcnVzdGFjZWFuLW9yaWctbm9zaGFkb3cucG5n.webp
+    //
+    // Or it may be encrypted, in which case it's prefixed by `enc/`, and the
+    // signature covers `enc/` and the ciphertext instead:
+    //
+    //   https://example.com/_image/36c6...5xE=/w_100,h_100/enc/3q2-7w...Ryw.webp
+    fn from_path(path: &'a str) -> Result<Self, VerifyError> {
+        let mut segments = path.trim_start_matches('/').splitn(3, '/');
+        let (Some(signature), Some(params), Some(target)) =
+            (segments.next(), segments.next(), segments.next())
+        else {
+            return Err(VerifyError::MalformedUrl);
+        };
+
+        // Percent-encoded URLs always hold an encoded scheme separator, which
+        // base64url cannot.
+        if target.contains(['%', ':']) {
+            return Ok(Self {
+                signature: signature.into(),
+                params: params.into(),
+                target: SignedTarget::Encoded(target.into()),
+            });
+        }
+
+        let (encoded, extension) = match target.split_once('.') {
+            Some((encoded, extension)) => (encoded, Some(extension)),
+            None => (target, None),
+        };
+
+        let target = match encoded.strip_prefix("enc/") {
+            Some(encrypted) => SignedTarget::Encrypted(encrypted.into()),
+            None => URL_SAFE_NO_PAD
+                .decode(encoded)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .map(|target| {
+                    SignedTarget::Encoded(urlencoding::encode(&target).into_owned().into())
+                })
+                .ok_or(VerifyError::MalformedUrl)?,
+        };
+        let mut params: TransformationParams = params.parse()?;
+
+        if let Some(extension) = extension {
+            let format = ImageFormat::from_extension(extension)
+                .map(OutputFormat::Format)
+                .ok_or(VerifyError::MalformedUrl)?;
+
+            // An extension which contradicts the format parameter is ambiguous.
+            if params.format.is_some_and(|requested| requested != format) {
+                return Err(VerifyError::MalformedUrl);
+            }
+
+            params.format = Some(format);
+        }
+
+        Ok(Self {
+            signature: signature.into(),
+            params: params.to_string().into(),
+            target,
+        })
+    }
+
+    // Expected format should follow:
+    //
+    //   ?url={image_url}&{transform_key1}={transform_value1}&...&s={signature}
+    //
+    // The signature is the same as that of the path layout, i.e. it covers the
+    // canonical transform parameters and the URL-encoded image URL. An encrypted
+    // image URL is given as `enc` rather than `url`, and signed as in the path
+    // layout.
+    //
+    // For example, a valid request might look like:
+    //
+    //   https://example.com/_image/?url=https%3A%2F%2Fwww.rustacean.net%2Fassets%2Frustacean-orig-noshadow.png&w=100&h=100&s=36c6...5xE%3D
+    //
+    // As with the path layout, the transform parameters must be in canonical
+    // form, so that any given transform has exactly one serialization.
+    fn from_query(query: &'a str) -> Result<Self, VerifyError> {
+        let pairs: Vec<(Cow<'a, str>, Cow<'a, str>)> =
+            form_urlencoded::parse(query.as_bytes()).collect();
+
+        // Repeated parameters would be ambiguous.
+        let find = |name: &str| {
+            let mut values = pairs
+                .iter()
+                .filter(|(key, _)| key == name)
+                .map(|(_, value)| value.clone());
+            match (values.next(), values.next()) {
+                (value, None) => Ok(value),
+                (_, Some(_)) => Err(VerifyError::MalformedUrl),
+            }
+        };
+
+        let signature = find("s")?.ok_or(VerifyError::MalformedUrl)?;
+        let target = match (find("url")?, find("enc")?) {
+            (Some(target), None) => {
+                SignedTarget::Encoded(urlencoding::encode(&target).into_owned().into())
+            }
+            (None, Some(encrypted)) => SignedTarget::Encrypted(encrypted),
+            _ => return Err(VerifyError::MalformedUrl),
+        };
+
+        let param_pairs: Vec<(&str, &str)> = pairs
+            .iter()
+            .filter(|(key, _)| !matches!(&**key, "s" | "url" | "enc"))
+            .map(|(key, value)| (&**key, &**value))
+            .collect();
+        let params = TransformationParams::from_pairs(param_pairs.iter().copied())
+            .map_err(in_query_layout)?;
+
+        // Values are checked when parsing, which leaves their order.
+        if !params
+            .pairs()
+            .map(|(key, _)| key)
+            .eq(param_pairs.iter().map(|(key, _)| *key))
+        {
+            return Err(in_query_layout(ParamsError::NonCanonical(params.to_string())).into());
+        }
+
+        Ok(Self {
+            signature,
+            params: params.to_string().into(),
+            target,
+        })
+    }
+}
+
+/// Renders the canonical parameters of a [`ParamsError::NonCanonical`], which
+/// are given in the path layout, in the query layout instead.
+fn in_query_layout(err: ParamsError) -> ParamsError {
+    match err {
+        ParamsError::NonCanonical(canonical) => ParamsError::NonCanonical(
+            form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(
+                    canonical
+                        .split(',')
+                        .filter_map(|param| param.split_once('_')),
+                )
+                .finish(),
+        ),
+        err => err,
+    }
+}
+
+/// Layout of a signed URL.
+///
+/// Both layouts carry the same signature, which covers the transform parameters
+/// and the URL-encoded target URL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UrlLayout {
+    /// Signature, parameters, and target URL as path segments, e.g.
+    /// `/{signature}/w_100,h_100/https%3A%2F%2Fexample.com%2Fimage.png`.
+    #[default]
+    Path,
+
+    /// Target URL, parameters, and signature as query parameters, e.g.
+    /// `?url=https%3A%2F%2Fexample.com%2Fimage.png&w=100&h=100&s={signature}`.
+    ///
+    /// This is useful where percent-encoded path segments are decoded or
+    /// otherwise altered in transit.
+    Query,
+}
+
+/// Encoding of the target URL.
+///
+/// Within the path layout, an output format set alongside any encoding other
+/// than [`TargetEncoding::Percent`] is given as a file extension instead of a
+/// parameter, e.g. `aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc.webp`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TargetEncoding {
+    /// Percent-encoded, e.g. `https%3A%2F%2Fexample.com%2Fimage.png`.
+    #[default]
+    Percent,
+
+    /// Unpadded base64url within the path layout, e.g.
+    /// `aHR0cHM6Ly9leGFtcGxlLmNvbS9pbWFnZS5wbmc`.
+    ///
+    /// This has no effect within the query layout, where the target URL is
+    /// always given as the `url` parameter.
+    Base64,
+
+    /// Encrypted with XChaCha20-Poly1305, so that the target URL isn't
+    /// revealed, e.g. `enc/{ciphertext}` within the path layout or
+    /// `enc={ciphertext}` within the query layout.
+    ///
+    /// The signature covers the ciphertext, which is only decrypted once the
+    /// signature is verified.
+    ///
+    /// The encryption key is derived from the signing key, unless another key
+    /// is given via [`SignedUrlBuilder::encryption_key`].
+    Encrypted,
+}
+
+/// Options which affect the form of a signed URL, rather than the
+/// transformation.
+#[derive(Debug, Clone)]
+struct UrlOptions {
+    layout: UrlLayout,
+    target_encoding: TargetEncoding,
+    encryption_key: Option<Key>,
+}
+
 /// Signed URL.
 #[derive(Debug)]
 pub struct SignedUrl {
-    base: Url,
-    key: Key,
+    signer: UrlSigner,
     params: TransformationParams,
     target: Url,
 }
 
 impl SignedUrl {
-    const fn new(key: Key, base: Url, target: Url, params: TransformationParams) -> Self {
-        Self {
+    /// Generates a signed URL.
+    ///
+    /// The signature is based on the parameters and encoded URL.
+    pub fn generate_signed_url(&self) -> Result<Url, url::ParseError> {
+        self.signer.sign(&self.params, &self.target)
+    }
+}
+
+/// Long-lived signer of URLs, for signing many URLs with the same key and base
+/// URL.
+///
+/// The keyed HMAC state is computed once, rather than for every URL, and the
+/// signer is cheap to clone and may be shared across threads.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::{Key, TransformationParams, UrlSigner};
+/// use url::Url;
+///
+/// # /*
+/// let key = { /* a cryptographically random key >= 64 bytes */ };
+/// # */
+/// # let key = Key::generate();
+///
+/// let signer = UrlSigner::new(key, Url::parse("https://example.com/_image/").unwrap());
+///
+/// let mut params = TransformationParams::default();
+/// params.width = Some(100);
+/// let target =
+///     Url::parse("https://www.rustacean.net/assets/rustacean-orig-noshadow.png").unwrap();
+///
+/// let signed_url = signer.sign(&params, &target).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct UrlSigner {
+    inner: Arc<SignerInner>,
+}
+
+#[derive(Clone)]
+struct SignerInner {
+    base: Url,
+    mac: Hmac<Sha256>,
+    key_id: Option<String>,
+    cipher: TargetCipher,
+    options: UrlOptions,
+}
+
+// Signers are meant to be shared across threads.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<UrlSigner>();
+};
+
+impl std::fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UrlSigner")
+            .field("base", &self.inner.base)
+            .field("key_id", &self.inner.key_id)
+            .field("layout", &self.inner.options.layout)
+            .field("target_encoding", &self.inner.options.target_encoding)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UrlSigner {
+    /// Create a new [`UrlSigner`] with the provided signing key and base URL.
+    ///
+    /// When given a [`KeyRing`], URLs are signed with its signing key and
+    /// prefixed by its key ID, if any.
+    pub fn new(key: impl Into<KeyRing>, base: Url) -> Self {
+        Self::with_options(
+            key.into(),
             base,
-            key,
-            params,
-            target,
+            UrlOptions {
+                layout: UrlLayout::Path,
+                target_encoding: TargetEncoding::Percent,
+                encryption_key: None,
+            },
+        )
+    }
+
+    fn with_options(key: KeyRing, base: Url, options: UrlOptions) -> Self {
+        let signing_key = key.signing_key();
+        Self {
+            inner: Arc::new(SignerInner {
+                base,
+                mac: Hmac::<Sha256>::new_from_slice(signing_key.as_slice())
+                    .expect("HMAC can take key of any size"),
+                key_id: key.key_id().map(str::to_owned),
+                cipher: TargetCipher::new(options.encryption_key.as_ref().unwrap_or(signing_key)),
+                options,
+            }),
         }
     }
 
-    fn sign(&self, data: &[u8]) -> String {
-        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_slice())
-            .expect("HMAC can take key of any size");
+    /// Set the layout of signed URLs.
+    ///
+    /// Defaults to [`UrlLayout::Path`].
+    pub fn set_layout(mut self, layout: UrlLayout) -> Self {
+        Arc::make_mut(&mut self.inner).options.layout = layout;
+        self
+    }
+
+    /// Set the encoding of target URLs.
+    ///
+    /// Defaults to [`TargetEncoding::Percent`].
+    pub fn set_target_encoding(mut self, target_encoding: TargetEncoding) -> Self {
+        Arc::make_mut(&mut self.inner).options.target_encoding = target_encoding;
+        self
+    }
+
+    /// Set the key which target URLs are encrypted with, when they're encoded
+    /// as [`TargetEncoding::Encrypted`].
+    ///
+    /// Defaults to the signing key.
+    pub fn set_encryption_key(mut self, encryption_key: Key) -> Self {
+        let inner = Arc::make_mut(&mut self.inner);
+        inner.cipher = TargetCipher::new(&encryption_key);
+        inner.options.encryption_key = Some(encryption_key);
+        self
+    }
+
+    fn signature(&self, data: &[u8]) -> String {
+        let mut mac = self.inner.mac.clone();
         mac.update(data);
-        URL_SAFE.encode(mac.finalize().into_bytes())
+        let signature = URL_SAFE.encode(mac.finalize().into_bytes());
+
+        match &self.inner.key_id {
+            Some(key_id) => format!("{key_id}.{signature}"),
+            None => signature,
+        }
     }
 
-    /// Generates a signed URL.
+    /// Generates a signed URL for transforming `target` with `params`.
     ///
     /// The signature is based on the parameters and encoded URL.
-    pub fn generate_signed_url(&self) -> Result<Url, url::ParseError> {
-        let params_encoded = self.params.to_string();
-        let url_encoded = urlencoding::encode(self.target.as_ref());
-        let combined_encoded = format!("{params_encoded}{url_encoded}");
-        let signature = self.sign(combined_encoded.as_bytes());
+    pub fn sign(
+        &self,
+        params: &TransformationParams,
+        target: &Url,
+    ) -> Result<Url, url::ParseError> {
+        let SignerInner {
+            base,
+            cipher,
+            options,
+            ..
+        } = &*self.inner;
 
-        self.base
-            .join(&format!("{signature}/{params_encoded}/{url_encoded}"))
+        let params_encoded = params.to_string();
+        let url_encoded = urlencoding::encode(target.as_ref());
+
+        let target_encoded = match options.target_encoding {
+            TargetEncoding::Percent => None,
+            TargetEncoding::Base64 => Some(URL_SAFE_NO_PAD.encode(target.as_str())),
+            TargetEncoding::Encrypted => Some(cipher.encrypt(target.as_str())),
+        };
+
+        // Encrypted target URLs are signed as such, so that they're only
+        // decrypted once verified.
+        let combined_encoded = match (options.target_encoding, &target_encoded) {
+            (TargetEncoding::Encrypted, Some(encrypted)) => {
+                format!("{params_encoded}enc/{encrypted}")
+            }
+            _ => format!("{params_encoded}{url_encoded}"),
+        };
+        let signature = self.signature(combined_encoded.as_bytes());
+
+        match options.layout {
+            UrlLayout::Path => {
+                let Some(target_encoded) = target_encoded else {
+                    return base.join(&format!("{signature}/{params_encoded}/{url_encoded}"));
+                };
+
+                let target_encoded = match options.target_encoding {
+                    TargetEncoding::Encrypted => format!("enc/{target_encoded}"),
+                    _ => target_encoded,
+                };
+
+                match params.format {
+                    Some(format @ OutputFormat::Format(_)) => {
+                        let params_encoded = TransformationParams {
+                            format: None,
+                            ..params.clone()
+                        };
+                        base.join(&format!(
+                            "{signature}/{params_encoded}/{target_encoded}.{format}"
+                        ))
+                    }
+
+                    _ => base.join(&format!("{signature}/{params_encoded}/{target_encoded}")),
+                }
+            }
+
+            UrlLayout::Query => {
+                let mut url = base.clone();
+                {
+                    let mut query = url.query_pairs_mut();
+                    query.clear();
+                    match options.target_encoding {
+                        TargetEncoding::Encrypted => {
+                            query.append_pair("enc", target_encoded.as_deref().unwrap_or_default())
+                        }
+                        _ => query.append_pair("url", target.as_str()),
+                    };
+                    for (key, value) in params.pairs() {
+                        query.append_pair(key, &value);
+                    }
+                    query.append_pair("s", &signature);
+                }
+                Ok(url)
+            }
+        }
     }
 }
 
@@ -104,6 +852,7 @@ pub struct SignedUrlBuilder<K, B, P, T> {
     base: B,
     params: P,
     target: T,
+    options: UrlOptions,
 }
 
 impl SignedUrlBuilder<(), (), (), ()> {
@@ -114,15 +863,25 @@ impl SignedUrlBuilder<(), (), (), ()> {
             base: (),
             params: (),
             target: (),
+            options: UrlOptions {
+                layout: UrlLayout::Path,
+                target_encoding: TargetEncoding::Percent,
+                encryption_key: None,
+            },
         }
     }
 
     /// Set signing key.
-    pub const fn key(self, key: Key) -> SignedUrlBuilder<Key, (), (), ()> {
+    ///
+    /// When given a [`KeyRing`], URLs are signed with its signing key and
+    /// prefixed by its key ID, if any.
+    pub fn key(self, key: impl Into<KeyRing>) -> SignedUrlBuilder<KeyRing, (), (), ()> {
+        let key = key.into();
         let Self {
             base,
             params,
             target,
+            options,
             ..
         } = self;
         SignedUrlBuilder {
@@ -130,17 +889,19 @@ impl SignedUrlBuilder<(), (), (), ()> {
             base,
             params,
             target,
+            options,
         }
     }
 }
 
-impl SignedUrlBuilder<Key, (), (), ()> {
+impl SignedUrlBuilder<KeyRing, (), (), ()> {
     /// Set base URL.
-    pub const fn base(self, base: Url) -> SignedUrlBuilder<Key, Url, (), ()> {
+    pub fn base(self, base: Url) -> SignedUrlBuilder<KeyRing, Url, (), ()> {
         let Self {
             key,
             params,
             target,
+            options,
             ..
         } = self;
         SignedUrlBuilder {
@@ -148,15 +909,20 @@ impl SignedUrlBuilder<Key, (), (), ()> {
             base,
             params,
             target,
+            options,
         }
     }
 }
 
-impl SignedUrlBuilder<Key, Url, (), ()> {
+impl SignedUrlBuilder<KeyRing, Url, (), ()> {
     /// Returns a builder on which parameters may be set.
-    pub fn params(self) -> SignedUrlBuilder<Key, Url, TransformationParams, ()> {
+    pub fn params(self) -> SignedUrlBuilder<KeyRing, Url, TransformationParams, ()> {
         let Self {
-            key, base, target, ..
+            key,
+            base,
+            target,
+            options,
+            ..
         } = self;
         let params = TransformationParams::default();
         SignedUrlBuilder {
@@ -164,11 +930,45 @@ impl SignedUrlBuilder<Key, Url, (), ()> {
             base,
             target,
             params,
+            options,
         }
     }
 }
 
-impl SignedUrlBuilder<Key, Url, TransformationParams, ()> {
+impl SignedUrlBuilder<KeyRing, Url, TransformationParams, ()> {
+    /// Set the name of a preset configured on the server via
+    /// [`ImageTransformerBuilder::add_preset`](crate::ImageTransformerBuilder::add_preset).
+    ///
+    /// Any other parameters set override those of the preset.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `preset` is empty or holds characters other than ASCII
+    /// alphanumerics, `-`, and `_`.
+    pub fn preset(self, preset: impl Into<String>) -> Self {
+        let preset = preset.into();
+        assert!(
+            is_valid_preset_name(&preset),
+            "preset name must be non-empty and consist of ASCII alphanumerics, `-`, and `_`"
+        );
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.preset = Some(preset);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
     /// Set resize height.
     pub fn height(self, height: Height) -> Self {
         let Self {
@@ -176,6 +976,7 @@ impl SignedUrlBuilder<Key, Url, TransformationParams, ()> {
             base,
             target,
             mut params,
+            options,
             ..
         } = self;
         params.height = Some(height);
@@ -184,6 +985,7 @@ impl SignedUrlBuilder<Key, Url, TransformationParams, ()> {
             base,
             target,
             params,
+            options,
         }
     }
 
@@ -194,6 +996,7 @@ impl SignedUrlBuilder<Key, Url, TransformationParams, ()> {
             base,
             target,
             mut params,
+            options,
             ..
         } = self;
         params.width = Some(width);
@@ -202,27 +1005,369 @@ impl SignedUrlBuilder<Key, Url, TransformationParams, ()> {
             base,
             target,
             params,
+            options,
+        }
+    }
+
+    /// Set how the image is fit to the resize dimensions.
+    ///
+    /// Defaults to [`Fit::Fill`].
+    pub fn fit(self, fit: Fit) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.fit = Some(fit);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the region to crop to before resizing.
+    pub fn crop(self, crop: Crop) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.crop = Some(crop);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the clockwise rotation after resizing, in degrees.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rotate` is not one of 90, 180, or 270.
+    pub fn rotate(self, rotate: u16) -> Self {
+        assert!(
+            geometry::is_valid_rotation(rotate),
+            "rotation must be one of 90, 180, or 270 degrees"
+        );
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.rotate = Some(rotate);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the direction in which to flip after resizing, before rotating.
+    pub fn flip(self, flip: Flip) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.flip = Some(flip);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set whether the image is converted to grayscale.
+    pub fn gray(self, gray: bool) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.gray = Some(gray);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set metadata policy, overriding the policy configured on the server.
+    pub fn metadata(self, metadata: MetadataPolicy) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.metadata = Some(metadata);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set whether the output is progressive (JPEG) or interlaced (PNG),
+    /// overriding the setting configured on the server.
+    pub fn progressive(self, progressive: bool) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.progressive = Some(progressive);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the number of palette colours, between 2 and 256, which PNG output
+    /// is quantized to.
+    pub fn colors(self, colors: u16) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.colors = Some(colors);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set whether dithering is applied when quantizing to a palette, which
+    /// requires [`colors`](Self::colors) or a preset setting them.
+    pub fn dither(self, dither: bool) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.dither = Some(dither);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the quality of lossy encoders, from 1 to 100.
+    ///
+    /// PNG and WebP are encoded losslessly, so ignore it. Where the client
+    /// accepts several formats equally, a lossy one is chosen if supported.
+    pub fn quality(self, quality: u8) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.quality = Some(quality);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the maximum size of the encoded image, in bytes.
+    ///
+    /// Quality, and dimensions where the server allows for it, are reduced
+    /// until the image fits. PNG and WebP are encoded losslessly, so only fit
+    /// by downscaling. Where the client accepts several formats equally, a
+    /// lossy one is chosen if supported.
+    pub fn max_bytes(self, max_bytes: u64) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.max_bytes = Some(max_bytes);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the output format.
+    pub fn format(self, format: OutputFormat) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.format = Some(format);
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
+        }
+    }
+
+    /// Set the time after which the URL is no longer valid.
+    ///
+    /// The expiry is covered by the signature and has a resolution of one
+    /// second.
+    pub fn expires_at(self, expires_at: SystemTime) -> Self {
+        let Self {
+            key,
+            base,
+            target,
+            mut params,
+            options,
+            ..
+        } = self;
+        params.expires = Some(
+            expires_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |expires| expires.as_secs()),
+        );
+        SignedUrlBuilder {
+            key,
+            base,
+            target,
+            params,
+            options,
         }
     }
 
     /// Set image target URL.
-    pub fn target(self, target: Url) -> SignedUrlBuilder<Key, Url, TransformationParams, Url> {
+    pub fn target(self, target: Url) -> SignedUrlBuilder<KeyRing, Url, TransformationParams, Url> {
         let Self {
-            key, base, params, ..
+            key,
+            base,
+            params,
+            options,
+            ..
         } = self;
         SignedUrlBuilder {
             key,
             base,
             target,
             params,
+            options,
         }
     }
 }
 
-impl SignedUrlBuilder<Key, Url, TransformationParams, Url> {
+impl SignedUrlBuilder<KeyRing, Url, TransformationParams, Url> {
     /// Returns a [`SignedUrl`].
     pub fn build(self) -> SignedUrl {
-        SignedUrl::new(self.key, self.base, self.target, self.params)
+        SignedUrl {
+            signer: UrlSigner::with_options(self.key, self.base, self.options),
+            params: self.params,
+            target: self.target,
+        }
+    }
+}
+
+impl<K, B, P, T> SignedUrlBuilder<K, B, P, T> {
+    /// Set the layout of the generated URL.
+    ///
+    /// Defaults to [`UrlLayout::Path`].
+    pub fn layout(self, layout: UrlLayout) -> Self {
+        Self {
+            options: UrlOptions {
+                layout,
+                ..self.options
+            },
+            ..self
+        }
+    }
+
+    /// Set the encoding of the target URL.
+    ///
+    /// Defaults to [`TargetEncoding::Percent`].
+    pub fn target_encoding(self, target_encoding: TargetEncoding) -> Self {
+        Self {
+            options: UrlOptions {
+                target_encoding,
+                ..self.options
+            },
+            ..self
+        }
+    }
+
+    /// Set the key which target URLs are encrypted with, when they're
+    /// encoded as [`TargetEncoding::Encrypted`].
+    ///
+    /// This must match the key configured via
+    /// [`ImageTransformerBuilder::set_encryption_key`](crate::ImageTransformerBuilder::set_encryption_key).
+    /// Defaults to the signing key.
+    pub fn encryption_key(self, encryption_key: Key) -> Self {
+        Self {
+            options: UrlOptions {
+                encryption_key: Some(encryption_key),
+                ..self.options
+            },
+            ..self
+        }
     }
 }
 
@@ -231,3 +1376,269 @@ impl Default for SignedUrlBuilder<(), (), (), ()> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = "https://example.com/image.png";
+
+    fn encrypted_url(key: impl Into<KeyRing>, layout: UrlLayout) -> Url {
+        UrlSigner::new(key, "https://example.com/_image/".parse().unwrap())
+            .set_layout(layout)
+            .set_target_encoding(TargetEncoding::Encrypted)
+            .sign(&TransformationParams::default(), &TARGET.parse().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn reuses_signers_across_urls_and_threads() {
+        let key = Key::generate();
+        let base: Url = "https://example.com/_image/".parse().unwrap();
+        let signer = UrlSigner::new(key.clone(), base.clone());
+        let verifier = Verifier::new(key.clone());
+
+        let urls: Vec<(TransformationParams, Url)> = (1..=4)
+            .map(|width| {
+                let params = TransformationParams {
+                    width: Some(width * 100),
+                    ..Default::default()
+                };
+                let target = format!("https://example.com/{width}.png").parse().unwrap();
+                (params, target)
+            })
+            .collect();
+
+        let signed: Vec<Url> = std::thread::scope(|scope| {
+            let handles: Vec<_> = urls
+                .iter()
+                .map(|(params, target)| {
+                    let signer = signer.clone();
+                    scope.spawn(move || signer.sign(params, target).unwrap())
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        for ((params, target), signed) in urls.iter().zip(&signed) {
+            // Signing doesn't carry state over from one URL to the next.
+            assert_eq!(signer.sign(params, target).unwrap(), *signed);
+            assert_eq!(
+                UrlSigner::new(key.clone(), base.clone())
+                    .sign(params, target)
+                    .unwrap(),
+                *signed
+            );
+
+            let verified = verifier.verify_url(signed.as_str()).unwrap();
+            assert_eq!(verified.params, *params);
+            assert_eq!(verified.target, *target);
+        }
+    }
+
+    #[test]
+    fn rejects_non_canonical_query_params() {
+        let key = Key::generate();
+        let mut params = TransformationParams::default();
+        (params.width, params.height) = (Some(100), Some(50));
+        let url = UrlSigner::new(key.clone(), "https://example.com/_image/".parse().unwrap())
+            .set_layout(UrlLayout::Query)
+            .sign(&params, &TARGET.parse().unwrap())
+            .unwrap();
+        let verifier = Verifier::new(key);
+
+        assert!(verifier.verify_url(url.as_str()).is_ok());
+
+        let reordered = url.as_str().replace("w=100&h=50", "h=50&w=100");
+        assert_eq!(
+            verifier.verify_url(&reordered).unwrap_err(),
+            VerifyError::InvalidParams(ParamsError::NonCanonical("w=100&h=50".to_owned()))
+        );
+
+        let padded = url.as_str().replace("w=100", "w=0100");
+        assert_eq!(
+            verifier.verify_url(&padded).unwrap_err(),
+            VerifyError::InvalidParams(ParamsError::NonCanonical("w=100".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_repeated_query_params() {
+        let key = Key::generate();
+        let params = TransformationParams {
+            width: Some(100),
+            ..Default::default()
+        };
+        let url = UrlSigner::new(key.clone(), "https://example.com/_image/".parse().unwrap())
+            .set_layout(UrlLayout::Query)
+            .sign(&params, &TARGET.parse().unwrap())
+            .unwrap();
+        let verifier = Verifier::new(key);
+
+        for repeated in [
+            format!("{url}&s=AAAA"),
+            format!("{url}&url=https%3A%2F%2Fevil.example.net%2Fimage.png"),
+            format!("{url}&enc=AAAA"),
+        ] {
+            assert_eq!(
+                verifier.verify_url(&repeated).unwrap_err(),
+                VerifyError::MalformedUrl,
+                "{repeated}"
+            );
+        }
+
+        let repeated = url.as_str().replace("w=100", "w=100&w=100");
+        assert_eq!(
+            verifier.verify_url(&repeated).unwrap_err(),
+            VerifyError::InvalidParams(ParamsError::Duplicate("w".to_owned()))
+        );
+    }
+
+    #[test]
+    fn round_trips_base64_targets_with_extensions() {
+        let key = Key::generate();
+        let params = TransformationParams {
+            width: Some(100),
+            format: Some(OutputFormat::Format(ImageFormat::WebP)),
+            ..Default::default()
+        };
+        let url = UrlSigner::new(key.clone(), "https://example.com/_image/".parse().unwrap())
+            .set_target_encoding(TargetEncoding::Base64)
+            .sign(&params, &TARGET.parse().unwrap())
+            .unwrap();
+        let verifier = Verifier::new(key);
+
+        assert_eq!(
+            url.path(),
+            format!(
+                "/_image/{}/w_100/{}.webp",
+                url.path_segments().unwrap().nth(1).unwrap(),
+                URL_SAFE_NO_PAD.encode(TARGET)
+            )
+        );
+        let verified = verifier.verify_url(url.as_str()).unwrap();
+        assert_eq!(verified.target.as_str(), TARGET);
+        assert_eq!(verified.params, params);
+
+        // The extension is covered by the signature.
+        let png = url.as_str().replace(".webp", ".png");
+        assert_eq!(
+            verifier.verify_url(&png).unwrap_err(),
+            VerifyError::Mismatch
+        );
+        let unknown = url.as_str().replace(".webp", ".foo");
+        assert_eq!(
+            verifier.verify_url(&unknown).unwrap_err(),
+            VerifyError::MalformedUrl
+        );
+    }
+
+    #[test]
+    fn reports_invalid_path_params() {
+        let verifier = Verifier::new(Key::generate());
+        let target = URL_SAFE_NO_PAD.encode(TARGET);
+
+        assert_eq!(
+            verifier
+                .verify_path_and_query(&format!("/sig/h_50,w_100/{target}"), None)
+                .unwrap_err(),
+            VerifyError::InvalidParams(ParamsError::NonCanonical("w_100,h_50".to_owned()))
+        );
+        assert_eq!(
+            verifier
+                .verify_path_and_query(&format!("/sig/x_1/{target}.png"), None)
+                .unwrap_err(),
+            VerifyError::InvalidParams(ParamsError::UnknownKey("x".to_owned()))
+        );
+    }
+
+    #[test]
+    fn round_trips_encrypted_targets() {
+        let key = Key::generate();
+
+        for layout in [UrlLayout::Path, UrlLayout::Query] {
+            let url = encrypted_url(key.clone(), layout);
+            assert!(!url.as_str().contains("example.com/image"));
+
+            let verified = Verifier::new(key.clone()).verify_url(url.as_str()).unwrap();
+            assert_eq!(verified.target.as_str(), TARGET);
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_encrypted_targets() {
+        let key = Key::generate();
+        let url = encrypted_url(key.clone(), UrlLayout::Path);
+        let (head, encrypted) = url.as_str().rsplit_once('/').unwrap();
+        let tampered = if encrypted.starts_with('A') { 'B' } else { 'A' };
+        let url = format!("{head}/{tampered}{}", &encrypted[1..]);
+
+        // The signature covers the ciphertext, so it's checked before decrypting.
+        assert_eq!(
+            Verifier::new(key).verify_url(&url).unwrap_err(),
+            VerifyError::Mismatch
+        );
+    }
+
+    #[test]
+    fn rejects_signed_targets_which_cannot_be_decrypted() {
+        let key = Key::generate();
+        let url = encrypted_url(key.clone(), UrlLayout::Query);
+        let verifier = Verifier::new(key).set_encryption_key(Key::generate());
+
+        assert_eq!(
+            verifier.verify_url(url.as_str()).unwrap_err(),
+            VerifyError::MalformedUrl
+        );
+    }
+
+    #[test]
+    fn decrypts_targets_of_rotated_keys() {
+        let (previous, current) = (Key::generate(), Key::generate());
+
+        let url = encrypted_url(previous.clone(), UrlLayout::Path);
+        let verifier =
+            Verifier::new(KeyRing::new(current.clone()).add_verification_key(previous.clone()));
+        assert_eq!(
+            verifier.verify_url(url.as_str()).unwrap().target.as_str(),
+            TARGET
+        );
+
+        let url = encrypted_url(
+            KeyRing::new(previous.clone()).set_key_id("previous"),
+            UrlLayout::Query,
+        );
+        let verifier = Verifier::new(
+            KeyRing::new(current)
+                .set_key_id("current")
+                .add_verification_key_with_id("previous", previous),
+        );
+        assert_eq!(
+            verifier.verify_url(url.as_str()).unwrap().target.as_str(),
+            TARGET
+        );
+    }
+
+    #[test]
+    fn rejects_encrypted_targets_of_unknown_key_ids() {
+        let (previous, current) = (Key::generate(), Key::generate());
+
+        let url = encrypted_url(
+            KeyRing::new(previous.clone()).set_key_id("previous"),
+            UrlLayout::Path,
+        );
+        let verifier = Verifier::new(
+            KeyRing::new(current)
+                .set_key_id("current")
+                .add_verification_key_with_id("other", previous),
+        );
+
+        assert_eq!(
+            verifier.verify_url(url.as_str()).unwrap_err(),
+            VerifyError::UnknownKeyId("previous".to_owned())
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Resolution of target URLs against a configured source URL.
+use url::Url;
+
+/// Returns `url` with a trailing slash, so that paths are resolved beneath it
+/// rather than beside it.
+pub(crate) fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+/// Resolves `path` against `source`, which must end with a slash.
+///
+/// Returns `None` when `path` is invalid or escapes the source, e.g. via `..`
+/// or as an absolute URL.
+pub(crate) fn resolve(source: &Url, path: &str) -> Option<Url> {
+    source
+        .join(path)
+        .ok()
+        .filter(|target| target.as_str().starts_with(source.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> Url {
+        with_trailing_slash("https://example.com/images".parse().unwrap())
+    }
+
+    #[test]
+    fn adds_trailing_slashes() {
+        assert_eq!(source().as_str(), "https://example.com/images/");
+        assert_eq!(with_trailing_slash(source()), source());
+    }
+
+    #[test]
+    fn resolves_paths_beneath_the_source() {
+        assert_eq!(
+            resolve(&source(), "a/b.png").unwrap().as_str(),
+            "https://example.com/images/a/b.png"
+        );
+        assert_eq!(
+            resolve(&source(), "a/../b.png").unwrap().as_str(),
+            "https://example.com/images/b.png"
+        );
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_source() {
+        for path in [
+            "../secret.png",
+            "a/../../secret.png",
+            "/secret.png",
+            "//evil.example.net/a.png",
+            "https://evil.example.net/a.png",
+            "\\\\evil.example.net/a.png",
+        ] {
+            assert_eq!(resolve(&source(), path), None, "{path}");
+        }
+    }
+}
@@ -0,0 +1,515 @@
+//! Thumbor URLs, so that URLs of an existing Thumbor deployment keep working.
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use hmac::{Hmac, Mac};
+use image::ImageFormat;
+use percent_encoding::percent_decode_str;
+use sha1::Sha1;
+use url::Url;
+
+use crate::{
+    geometry::{self, Crop, Flip},
+    image_type::OutputFormat,
+    metadata::MetadataPolicy,
+    resize::Fit,
+    signed::{VerifiedUrl, VerifyError},
+    source::{self, with_trailing_slash},
+    transformation_params::TransformationParams,
+};
+
+/// Length of an HMAC-SHA1 digest, in bytes.
+const DIGEST_LENGTH: usize = 20;
+
+/// Maximum output width and height, as with imgix URLs.
+const MAX_DIMENSION: u32 = 8192;
+
+/// Signature placeholder of unsafe URLs.
+const UNSAFE: &str = "unsafe";
+
+/// Configuration of Thumbor URLs.
+///
+/// Requests follow the grammar of Thumbor, e.g.
+/// `/{signature}/0x0:400x300/fit-in/300x200/smart/filters:quality(80)/{image}`,
+/// where the signature is the URL-safe base64 encoded HMAC-SHA1 of everything
+/// after it, keyed with the security key of the Thumbor deployment.
+///
+/// Crops, `fit-in` with and without the `upscale()` filter, flips, and the
+/// `quality`, `format`, `grayscale`, `rotate`, `max_bytes`, `strip_icc`, and
+/// `strip_exif` filters are supported. Smart cropping falls back to cropping
+/// around the centre, and other filters are ignored, as Thumbor does with
+/// filters it doesn't know. Requests for `meta`, `trim`, `adaptive-fit-in`,
+/// `full-fit-in`, or alignments other than the centre are rejected.
+///
+/// Images are URLs, with `http://` assumed when they don't have a scheme, or
+/// paths relative to the source, if one is set. Sizes are scaled down to at
+/// most 8192 pixels wide and high, keeping their aspect ratio.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::Thumbor;
+///
+/// let thumbor = Thumbor::new("MY_SECURE_KEY");
+/// assert_eq!(
+///     thumbor.sign_path("300x200/smart/example.com/image.jpg"),
+///     "/BoI59TTwsljW7xjr6JbNq5TG_Go=/300x200/smart/example.com/image.jpg",
+/// );
+/// ```
+#[derive(Clone)]
+pub struct Thumbor {
+    mac: Hmac<Sha1>,
+    allow_unsafe: bool,
+    source: Option<Url>,
+}
+
+impl Thumbor {
+    /// Create a new [`Thumbor`] with the given security key.
+    pub fn new(security_key: impl AsRef<[u8]>) -> Self {
+        Self {
+            mac: Hmac::new_from_slice(security_key.as_ref())
+                .expect("HMAC can take key of any size"),
+            allow_unsafe: false,
+            source: None,
+        }
+    }
+
+    /// Configure whether unsigned URLs, which have `unsafe` in place of the
+    /// signature, are accepted.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// # Panics
+    ///
+    /// [`ImageTransformerBuilder::build`](crate::ImageTransformerBuilder::build)
+    /// panics if this is set without a source, since any image on the web
+    /// could be requested otherwise.
+    pub fn set_allow_unsafe(self, allow_unsafe: bool) -> Self {
+        Self {
+            allow_unsafe,
+            ..self
+        }
+    }
+
+    /// Set the URL which images are resolved against, instead of being URLs
+    /// of their own.
+    pub fn set_source(self, source: Url) -> Self {
+        Self {
+            source: Some(with_trailing_slash(source)),
+            ..self
+        }
+    }
+
+    /// Returns whether unsafe URLs are restricted to the source, i.e. they're
+    /// only allowed along with a source.
+    pub(crate) const fn has_valid_unsafe(&self) -> bool {
+        !self.allow_unsafe || self.source.is_some()
+    }
+
+    /// Signs a path, e.g. `fit-in/300x200/example.com/image.jpg`, returning
+    /// the path with its signature prepended.
+    pub fn sign_path(&self, path: &str) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(path.as_bytes());
+        format!("/{}/{path}", URL_SAFE.encode(mac.finalize().into_bytes()))
+    }
+
+    /// Verifies the path of a request, relative to where the service is
+    /// mounted, and resolves it into transform parameters and a target URL.
+    pub(crate) fn resolve(&self, path: &str) -> Result<VerifiedUrl, VerifyError> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let (signature, path) = path.split_once('/').ok_or(VerifyError::MalformedUrl)?;
+
+        if signature == UNSAFE {
+            if !self.allow_unsafe {
+                return Err(VerifyError::Unsigned);
+            }
+        } else {
+            self.verify(signature, path)?;
+        }
+
+        let segments: Vec<&str> = path.split('/').collect();
+        let mut rest = &segments[..];
+
+        if take(&mut rest, |segment| {
+            (segment == "meta" || segment == "trim" || segment.starts_with("trim:")).then_some(())
+        })
+        .is_some()
+        {
+            return Err(VerifyError::MalformedUrl);
+        }
+
+        let crop = take(&mut rest, parse_crop);
+
+        let fit_in = take(&mut rest, |segment| match segment {
+            "fit-in" => Some(true),
+            "adaptive-fit-in" | "full-fit-in" | "adaptive-full-fit-in" => Some(false),
+            _ => None,
+        });
+        if fit_in == Some(false) {
+            return Err(VerifyError::MalformedUrl);
+        }
+
+        let size = take(&mut rest, parse_size).unwrap_or_default();
+
+        let centred = [["left", "center", "right"], ["top", "middle", "bottom"]]
+            .iter()
+            .all(|alignments| {
+                take(&mut rest, |segment| {
+                    alignments
+                        .contains(&segment)
+                        .then_some(segment == alignments[1])
+                })
+                .unwrap_or(true)
+            });
+        if !centred {
+            return Err(VerifyError::MalformedUrl);
+        }
+
+        // Without detectors, Thumbor crops around the centre, too.
+        take(&mut rest, |segment| (segment == "smart").then_some(()));
+
+        let filters = take(&mut rest, |segment| segment.strip_prefix("filters:"))
+            .map_or(Some(Vec::new()), parse_filters)
+            .ok_or(VerifyError::MalformedUrl)?;
+
+        let image = rest.join("/");
+        if image.is_empty() {
+            return Err(VerifyError::MalformedUrl);
+        }
+
+        let (width, height) = geometry::scale_down(
+            size.width.map(f64::from),
+            size.height.map(f64::from),
+            MAX_DIMENSION,
+        );
+        let mut params = TransformationParams {
+            width,
+            height,
+            crop,
+            flip: match (size.flip_horizontal, size.flip_vertical) {
+                (true, true) => Some(Flip::Both),
+                (true, false) => Some(Flip::Horizontal),
+                (false, true) => Some(Flip::Vertical),
+                (false, false) => None,
+            },
+            ..Default::default()
+        };
+
+        let mut upscale = false;
+        for (name, argument) in filters {
+            match name {
+                "quality" => {
+                    params.quality = Some(
+                        argument
+                            .parse()
+                            .ok()
+                            .filter(|quality| (1..=100).contains(quality))
+                            .ok_or(VerifyError::MalformedUrl)?,
+                    )
+                }
+                "format" => {
+                    params.format = Some(OutputFormat::Format(
+                        ImageFormat::from_extension(argument).ok_or(VerifyError::MalformedUrl)?,
+                    ))
+                }
+                "grayscale" => params.gray = Some(true),
+                "rotate" => {
+                    // Thumbor rotates counter-clockwise.
+                    let degrees = argument
+                        .parse::<u16>()
+                        .map_err(|_| VerifyError::MalformedUrl)?
+                        % 360;
+                    params.rotate = match (360 - degrees) % 360 {
+                        0 => None,
+                        degrees if geometry::is_valid_rotation(degrees) => Some(degrees),
+                        _ => return Err(VerifyError::MalformedUrl),
+                    }
+                }
+                "max_bytes" => {
+                    params.max_bytes =
+                        Some(argument.parse().map_err(|_| VerifyError::MalformedUrl)?)
+                }
+                "strip_icc" | "strip_exif" => params.metadata = Some(MetadataPolicy::Strip),
+                "upscale" => upscale = true,
+                _ => {}
+            }
+        }
+
+        if params.width.is_some() || params.height.is_some() {
+            params.fit = Some(match (fit_in.is_some(), upscale) {
+                (false, _) => Fit::Cover,
+                (true, false) => Fit::ScaleDown,
+                (true, true) => Fit::Contain,
+            });
+        }
+
+        Ok(VerifiedUrl {
+            params,
+            target: self.target(&image)?,
+        })
+    }
+
+    fn target(&self, image: &str) -> Result<Url, VerifyError> {
+        let Some(source) = &self.source else {
+            let image = percent_decode_str(image)
+                .decode_utf8()
+                .map_err(|_| VerifyError::InvalidTarget)?;
+
+            let target: Url = if image.contains("://") {
+                image.parse()
+            } else {
+                format!("http://{image}").parse()
+            }
+            .map_err(|_| VerifyError::InvalidTarget)?;
+
+            if !matches!(target.scheme(), "http" | "https") {
+                return Err(VerifyError::InvalidTarget);
+            }
+
+            return Ok(target);
+        };
+
+        source::resolve(source, image).ok_or(VerifyError::InvalidTarget)
+    }
+
+    fn verify(&self, signature: &str, path: &str) -> Result<(), VerifyError> {
+        let digest = URL_SAFE
+            .decode(signature)
+            .map_err(|_| VerifyError::MalformedEncoding)?;
+
+        if digest.len() != DIGEST_LENGTH {
+            return Err(VerifyError::WrongLength {
+                expected: DIGEST_LENGTH,
+                actual: digest.len(),
+            });
+        }
+
+        let mut mac = self.mac.clone();
+        mac.update(path.as_bytes());
+        mac.verify_slice(&digest).map_err(|_| VerifyError::Mismatch)
+    }
+}
+
+impl std::fmt::Debug for Thumbor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Thumbor")
+            .field("allow_unsafe", &self.allow_unsafe)
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Dimensions and flips of a Thumbor URL, e.g. `-300x0`.
+#[derive(Debug, Default, Clone, Copy)]
+struct Size {
+    width: Option<u32>,
+    height: Option<u32>,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+}
+
+/// Takes the next segment if `parse` accepts it.
+fn take<'a, T>(rest: &mut &[&'a str], parse: impl FnOnce(&'a str) -> Option<T>) -> Option<T> {
+    let (segment, tail) = rest.split_first()?;
+    let value = parse(segment)?;
+    *rest = tail;
+    Some(value)
+}
+
+/// Parses a crop of the form `{left}x{top}:{right}x{bottom}`.
+fn parse_crop(segment: &str) -> Option<Crop> {
+    let (top_left, bottom_right) = segment.split_once(':')?;
+    let (left, top) = parse_pair(top_left)?;
+    let (right, bottom) = parse_pair(bottom_right)?;
+
+    Some(Crop {
+        x: left,
+        y: top,
+        width: right.checked_sub(left).filter(|&width| width > 0)?,
+        height: bottom.checked_sub(top).filter(|&height| height > 0)?,
+    })
+}
+
+fn parse_pair(pair: &str) -> Option<(u32, u32)> {
+    let (x, y) = pair.split_once('x')?;
+    Some((parse_number(x)?, parse_number(y)?))
+}
+
+/// Parses a size of the form `{width}x{height}`, where either dimension may be
+/// empty or zero, i.e. proportional, and negative, i.e. flipped.
+fn parse_size(segment: &str) -> Option<Size> {
+    let (width, height) = segment.split_once('x')?;
+    let (flip_horizontal, width) = parse_dimension(width)?;
+    let (flip_vertical, height) = parse_dimension(height)?;
+
+    Some(Size {
+        width,
+        height,
+        flip_horizontal,
+        flip_vertical,
+    })
+}
+
+fn parse_dimension(dimension: &str) -> Option<(bool, Option<u32>)> {
+    let (flipped, dimension) = match dimension.strip_prefix('-') {
+        Some(dimension) => (true, dimension),
+        None => (false, dimension),
+    };
+
+    if dimension.is_empty() {
+        return Some((flipped, None));
+    }
+
+    Some((
+        flipped,
+        Some(parse_number(dimension)?).filter(|&dimension| dimension > 0),
+    ))
+}
+
+fn parse_number(number: &str) -> Option<u32> {
+    number
+        .bytes()
+        .all(|byte| byte.is_ascii_digit())
+        .then(|| number.parse().ok())
+        .flatten()
+}
+
+/// Parses filters of the form `{name}({argument}):{name}({argument})`.
+fn parse_filters(mut filters: &str) -> Option<Vec<(&str, &str)>> {
+    let mut parsed = Vec::new();
+
+    while !filters.is_empty() {
+        let (name, rest) = filters.split_once('(')?;
+
+        // Arguments may hold parentheses themselves, e.g. `fill(rgb(0,0,0))`.
+        let mut depth = 1;
+        let end = rest.char_indices().find_map(|(index, char)| {
+            match char {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            (depth == 0).then_some(index)
+        })?;
+
+        parsed.push((name, &rest[..end]));
+
+        filters = &rest[end + 1..];
+        if !filters.is_empty() {
+            filters = filters
+                .strip_prefix(':')
+                .filter(|filters| !filters.is_empty())?;
+        }
+    }
+
+    Some(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "my-security-key";
+
+    fn resolve(path: &str) -> Result<VerifiedUrl, VerifyError> {
+        Thumbor::new(KEY).resolve(&Thumbor::new(KEY).sign_path(path))
+    }
+
+    #[test]
+    fn signs_known_vectors() {
+        // Computed independently with HMAC-SHA1 and URL-safe base64.
+        let thumbor = Thumbor::new(KEY);
+
+        assert_eq!(
+            thumbor.sign_path("fit-in/300x200/filters:rotate(90)/example.com/image.jpg"),
+            "/l7nRvZB3wea15exoZBQ1t5m_7C4=/fit-in/300x200/filters:rotate(90)/example.com/image.jpg"
+        );
+        assert_eq!(
+            thumbor.sign_path(
+                "0x0:400x300/-300x-200/filters:quality(80):format(webp)/example.com/a%20b.jpg"
+            ),
+            "/WISPqID2h_wk8syk-P1p7RLBV9U=/0x0:400x300/-300x-200/filters:quality(80):format(webp)/\
+             example.com/a%20b.jpg"
+        );
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let thumbor = Thumbor::new(KEY);
+
+        assert!(thumbor
+            .resolve(
+                "/l7nRvZB3wea15exoZBQ1t5m_7C4=/fit-in/300x200/filters:rotate(90)/example.com/\
+                 image.jpg"
+            )
+            .is_ok());
+        assert_eq!(
+            thumbor
+                .resolve(
+                    "/l7nRvZB3wea15exoZBQ1t5m_7C4=/fit-in/300x201/filters:rotate(90)/example.com/\
+                     image.jpg"
+                )
+                .unwrap_err(),
+            VerifyError::Mismatch
+        );
+        assert_eq!(
+            thumbor
+                .resolve("/unsafe/300x200/example.com/image.jpg")
+                .unwrap_err(),
+            VerifyError::Unsigned
+        );
+    }
+
+    #[test]
+    fn rotates_counter_clockwise() {
+        let rotation = |degrees: u16| {
+            resolve(&format!("filters:rotate({degrees})/example.com/image.jpg"))
+                .map(|verified_url| verified_url.params.rotate)
+        };
+
+        assert_eq!(rotation(90), Ok(Some(270)));
+        assert_eq!(rotation(180), Ok(Some(180)));
+        assert_eq!(rotation(270), Ok(Some(90)));
+        assert_eq!(rotation(450), Ok(Some(270)));
+        assert_eq!(rotation(360), Ok(None));
+        assert!(rotation(45).is_err());
+    }
+
+    #[test]
+    fn limits_sizes() {
+        let dimensions = |size: &str| {
+            resolve(&format!("{size}/example.com/image.jpg"))
+                .map(|verified_url| (verified_url.params.width, verified_url.params.height))
+        };
+
+        assert_eq!(dimensions("300x200"), Ok((Some(300), Some(200))));
+        assert_eq!(dimensions("8192x0"), Ok((Some(8192), None)));
+        assert_eq!(dimensions("16384x4096"), Ok((Some(8192), Some(2048))));
+        assert_eq!(dimensions("x65535"), Ok((None, Some(8192))));
+        assert_eq!(
+            dimensions("4294967295x4294967295"),
+            Ok((Some(8192), Some(8192)))
+        );
+    }
+
+    #[test]
+    fn allows_unsafe_urls_within_the_source() {
+        let thumbor = Thumbor::new(KEY)
+            .set_allow_unsafe(true)
+            .set_source("https://images.example.com/scans".parse().unwrap());
+        assert!(thumbor.has_valid_unsafe());
+        assert!(!Thumbor::new(KEY).set_allow_unsafe(true).has_valid_unsafe());
+        assert!(Thumbor::new(KEY).has_valid_unsafe());
+
+        let verified_url = thumbor.resolve("/unsafe/300x200/a/image.jpg").unwrap();
+        assert_eq!(
+            verified_url.target.as_str(),
+            "https://images.example.com/scans/a/image.jpg"
+        );
+        assert_eq!(
+            thumbor
+                .resolve("/unsafe/300x200/../secret.jpg")
+                .unwrap_err(),
+            VerifyError::InvalidTarget
+        );
+    }
+}
@@ -1,51 +1,427 @@
 use std::str::FromStr;
 
+use crate::{
+    geometry::{self, Crop, Flip},
+    image_type::OutputFormat,
+    metadata::MetadataPolicy,
+    quantize::{MAX_COLORS, MIN_COLORS},
+    resize::Fit,
+};
+
+/// Resize width, in pixels.
 pub type Width = u32;
+
+/// Resize height, in pixels.
 pub type Height = u32;
 
-#[derive(Debug, Default)]
+/// Transform parameters of a signed URL.
+///
+/// These are written as comma-separated `{key}_{value}` pairs, e.g.
+/// `w_100,h_100`, in canonical order. Parsing is strict, see
+/// [`ParamsError`].
+///
+/// Parameters may be added in future, so this is built from
+/// [`Default::default`] rather than a struct expression.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub struct TransformationParams {
+    /// Name of a preset configured on the server, whose parameters are
+    /// overridden by any others given (`p`).
+    pub preset: Option<String>,
+
+    /// Resize width (`w`).
     pub width: Option<Width>,
+
+    /// Resize height (`h`).
     pub height: Option<Height>,
+
+    /// How the image is fit to the resize dimensions (`fit`).
+    pub fit: Option<Fit>,
+
+    /// Region to crop to before resizing (`crop`).
+    pub crop: Option<Crop>,
+
+    /// Clockwise rotation after resizing, in degrees (`rot`).
+    pub rotate: Option<u16>,
+
+    /// Direction in which to flip after resizing, before rotating (`flip`).
+    pub flip: Option<Flip>,
+
+    /// Whether the image is converted to grayscale (`gray`).
+    pub gray: Option<bool>,
+
+    /// Metadata policy (`md`).
+    pub metadata: Option<MetadataPolicy>,
+
+    /// Whether the output is progressive or interlaced (`pr`).
+    pub progressive: Option<bool>,
+
+    /// Number of palette colours PNG output is quantized to (`colors`).
+    pub colors: Option<u16>,
+
+    /// Whether dithering is applied when quantizing (`dither`).
+    pub dither: Option<bool>,
+
+    /// Quality of lossy encoders, from 1 to 100 (`q`).
+    ///
+    /// PNG and WebP are encoded losslessly, so ignore it.
+    pub quality: Option<u8>,
+
+    /// Maximum size of the encoded image, in bytes (`maxbytes`).
+    ///
+    /// PNG and WebP are encoded losslessly, so only fit by downscaling.
+    pub max_bytes: Option<u64>,
+
+    /// Output format (`f`).
+    pub format: Option<OutputFormat>,
+
+    /// Expiry of the URL, in seconds since the Unix epoch (`exp`).
+    pub expires: Option<u64>,
 }
 
-impl FromStr for TransformationParams {
-    type Err = &'static str;
-
-    fn from_str(params: &str) -> Result<Self, Self::Err> {
-        let mut width: Option<Width> = None;
-        let mut height: Option<Height> = None;
-
-        for param in params.split(',') {
-            if let Some((key, value)) = param.split_once('_') {
-                match key {
-                    "w" => width = value.parse().ok(),
-                    "h" => height = value.parse().ok(),
-                    _ => return Err("Invalid parameter"),
-                }
+/// Reason transform parameters could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParamsError {
+    /// A parameter is not of the form `{key}_{value}`.
+    #[error("parameter `{0}` is not of the form `{{key}}_{{value}}`")]
+    MissingSeparator(String),
+
+    /// A parameter has an unknown key.
+    #[error("unknown parameter `{0}`")]
+    UnknownKey(String),
+
+    /// A parameter has a value which cannot be parsed or is out of range.
+    #[error("invalid value `{value}` for parameter `{key}`")]
+    InvalidValue {
+        /// Key of the parameter.
+        key: String,
+
+        /// Value of the parameter.
+        value: String,
+    },
+
+    /// A parameter is given more than once.
+    #[error("duplicate parameter `{0}`")]
+    Duplicate(String),
+
+    /// A parameter is given without another one it depends on.
+    #[error("parameter `{key}` requires parameter `{required}`")]
+    MissingRequired {
+        /// Key of the parameter.
+        key: String,
+
+        /// Key of the parameter it depends on.
+        required: String,
+    },
+
+    /// Parameters are valid, but not in canonical form, i.e. not in canonical
+    /// order or with values not written as they would be serialized.
+    #[error("parameters are not in canonical form, expected `{0}`")]
+    NonCanonical(String),
+}
+
+impl TransformationParams {
+    /// Parses parameters from key-value pairs, such as `("w", "100")`, in any
+    /// order.
+    pub(crate) fn from_pairs<'a>(
+        pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, ParamsError> {
+        let mut params = Self::default();
+
+        for (key, value) in pairs {
+            match key {
+                "p" => set(
+                    &mut params.preset,
+                    key,
+                    parse_value(key, value, |name: &String| is_valid_preset_name(name))?,
+                )?,
+                "w" => set(&mut params.width, key, parse_value(key, value, |w| *w > 0)?)?,
+                "h" => set(
+                    &mut params.height,
+                    key,
+                    parse_value(key, value, |h| *h > 0)?,
+                )?,
+                "fit" => set(&mut params.fit, key, parse_value(key, value, |_| true)?)?,
+                "crop" => set(&mut params.crop, key, parse_value(key, value, |_| true)?)?,
+                "rot" => set(
+                    &mut params.rotate,
+                    key,
+                    parse_value(key, value, |&degrees| geometry::is_valid_rotation(degrees))?,
+                )?,
+                "flip" => set(&mut params.flip, key, parse_value(key, value, |_| true)?)?,
+                "gray" => set(&mut params.gray, key, parse_flag(key, value)?)?,
+                "md" => set(
+                    &mut params.metadata,
+                    key,
+                    parse_value(key, value, |_| true)?,
+                )?,
+                "pr" => set(&mut params.progressive, key, parse_flag(key, value)?)?,
+                "colors" => set(
+                    &mut params.colors,
+                    key,
+                    parse_value(key, value, |n| (MIN_COLORS..=MAX_COLORS).contains(n))?,
+                )?,
+                "dither" => set(&mut params.dither, key, parse_flag(key, value)?)?,
+                "q" => set(
+                    &mut params.quality,
+                    key,
+                    parse_value(key, value, |q| (1..=100).contains(q))?,
+                )?,
+                "maxbytes" => set(
+                    &mut params.max_bytes,
+                    key,
+                    parse_value(key, value, |_| true)?,
+                )?,
+                "f" => set(&mut params.format, key, parse_value(key, value, |_| true)?)?,
+                "exp" => set(&mut params.expires, key, parse_value(key, value, |_| true)?)?,
+                _ => return Err(ParamsError::UnknownKey(key.to_owned())),
             }
         }
 
-        Ok(Self { width, height })
+        // Presets may provide the palette, so they're checked once merged.
+        if params.preset.is_none() {
+            params.check_dependencies()?;
+        }
+
+        Ok(params)
+    }
+
+    /// Checks that parameters which only apply alongside others are given
+    /// with them.
+    pub(crate) fn check_dependencies(&self) -> Result<(), ParamsError> {
+        if self.dither.is_some() && self.colors.is_none() {
+            return Err(ParamsError::MissingRequired {
+                key: "dither".to_owned(),
+                required: "colors".to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the parameters which are set as key-value pairs, in canonical
+    /// order.
+    pub(crate) fn pairs(&self) -> impl Iterator<Item = (&'static str, String)> {
+        [
+            self.preset.as_ref().map(|p| ("p", p.clone())),
+            self.width.map(|w| ("w", w.to_string())),
+            self.height.map(|h| ("h", h.to_string())),
+            self.fit.map(|fit| ("fit", fit.to_string())),
+            self.crop.map(|crop| ("crop", crop.to_string())),
+            self.rotate.map(|rot| ("rot", rot.to_string())),
+            self.flip.map(|flip| ("flip", flip.to_string())),
+            self.gray.map(|gray| ("gray", u8::from(gray).to_string())),
+            self.metadata.map(|md| ("md", md.to_string())),
+            self.progressive.map(|pr| ("pr", u8::from(pr).to_string())),
+            self.colors.map(|colors| ("colors", colors.to_string())),
+            self.dither
+                .map(|dither| ("dither", u8::from(dither).to_string())),
+            self.quality.map(|q| ("q", q.to_string())),
+            self.max_bytes
+                .map(|max_bytes| ("maxbytes", max_bytes.to_string())),
+            self.format.map(|f| ("f", f.to_string())),
+            self.expires.map(|exp| ("exp", exp.to_string())),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Returns these parameters on top of those of a preset, i.e. with each
+    /// parameter which is not set taken from `preset`.
+    pub(crate) fn with_preset(self, preset: &Self) -> Self {
+        Self {
+            preset: self.preset,
+            width: self.width.or(preset.width),
+            height: self.height.or(preset.height),
+            fit: self.fit.or(preset.fit),
+            crop: self.crop.or(preset.crop),
+            rotate: self.rotate.or(preset.rotate),
+            flip: self.flip.or(preset.flip),
+            gray: self.gray.or(preset.gray),
+            metadata: self.metadata.or(preset.metadata),
+            progressive: self.progressive.or(preset.progressive),
+            colors: self.colors.or(preset.colors),
+            dither: self.dither.or(preset.dither),
+            quality: self.quality.or(preset.quality),
+            max_bytes: self.max_bytes.or(preset.max_bytes),
+            format: self.format.or(preset.format),
+            expires: self.expires,
+        }
+    }
+}
+
+/// Returns whether `name` is valid as the name of a preset, i.e. non-empty and
+/// consisting of ASCII alphanumerics, `-`, and `_`.
+pub(crate) fn is_valid_preset_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Parses parameters strictly: every parameter must be known and valid, given
+/// at most once, and in canonical form, so that any given transform has exactly
+/// one serialization.
+impl FromStr for TransformationParams {
+    type Err = ParamsError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let pairs = input
+            .split(',')
+            .map(|param| {
+                param
+                    .split_once('_')
+                    .ok_or_else(|| ParamsError::MissingSeparator(param.to_owned()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let params = Self::from_pairs(pairs)?;
+
+        let canonical = params.to_string();
+        if canonical != input {
+            return Err(ParamsError::NonCanonical(canonical));
+        }
+
+        Ok(params)
     }
 }
 
 impl std::fmt::Display for TransformationParams {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let params = [
-            self.width.map(|w| format!("w_{}", w)),
-            self.height.map(|h| format!("h_{}", h)),
-        ];
+        let mut params_iter = self.pairs();
 
-        let mut params_iter = params.into_iter().flatten();
-
-        if let Some(first_param) = params_iter.next() {
-            write!(f, "{}", first_param)?;
-            for param in params_iter {
-                write!(f, ",{}", param)?;
+        if let Some((key, value)) = params_iter.next() {
+            write!(f, "{}_{}", key, value)?;
+            for (key, value) in params_iter {
+                write!(f, ",{}_{}", key, value)?;
             }
         }
 
         Ok(())
     }
 }
+
+fn set<T>(slot: &mut Option<T>, key: &str, value: T) -> Result<(), ParamsError> {
+    if slot.replace(value).is_some() {
+        return Err(ParamsError::Duplicate(key.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Parses `value`, requiring it to be within range and written as it would be
+/// serialized.
+fn parse_value<T>(
+    key: &str,
+    value: &str,
+    in_range: impl FnOnce(&T) -> bool,
+) -> Result<T, ParamsError>
+where
+    T: FromStr + std::fmt::Display,
+{
+    let parsed = value
+        .parse::<T>()
+        .ok()
+        .filter(in_range)
+        .ok_or_else(|| invalid_value(key, value))?;
+
+    let canonical = parsed.to_string();
+    if canonical != value {
+        return Err(ParamsError::NonCanonical(format!("{key}_{canonical}")));
+    }
+
+    Ok(parsed)
+}
+
+fn parse_flag(key: &str, value: &str) -> Result<bool, ParamsError> {
+    match value {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(invalid_value(key, value)),
+    }
+}
+
+pub(crate) fn invalid_value(key: &str, value: &str) -> ParamsError {
+    ParamsError::InvalidValue {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_canonical_params() {
+        let params: TransformationParams = "w_100,h_50,fit_cover,q_80,f_webp".parse().unwrap();
+
+        assert_eq!(params.width, Some(100));
+        assert_eq!(params.height, Some(50));
+        assert_eq!(params.to_string(), "w_100,h_50,fit_cover,q_80,f_webp");
+        assert_eq!("".parse(), Ok(TransformationParams::default()));
+    }
+
+    #[test]
+    fn rejects_non_canonical_params() {
+        assert_eq!(
+            "h_50,w_100".parse::<TransformationParams>(),
+            Err(ParamsError::NonCanonical("w_100,h_50".to_owned()))
+        );
+        assert_eq!(
+            "w_0100".parse::<TransformationParams>(),
+            Err(ParamsError::NonCanonical("w_100".to_owned()))
+        );
+        assert_eq!(
+            "w_100,w_100".parse::<TransformationParams>(),
+            Err(ParamsError::Duplicate("w".to_owned()))
+        );
+        assert_eq!(
+            "w100".parse::<TransformationParams>(),
+            Err(ParamsError::MissingSeparator("w100".to_owned()))
+        );
+        assert_eq!(
+            "x_1".parse::<TransformationParams>(),
+            Err(ParamsError::UnknownKey("x".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_zero_dimensions() {
+        for params in ["w_0", "h_0"] {
+            assert!(matches!(
+                params.parse::<TransformationParams>(),
+                Err(ParamsError::InvalidValue { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_dither_without_colors() {
+        assert_eq!(
+            "dither_1".parse::<TransformationParams>(),
+            Err(ParamsError::MissingRequired {
+                key: "dither".to_owned(),
+                required: "colors".to_owned(),
+            })
+        );
+        assert!("colors_16,dither_1".parse::<TransformationParams>().is_ok());
+    }
+
+    #[test]
+    fn defers_dither_checks_of_presets() {
+        let params: TransformationParams = "p_thumb,dither_1".parse().unwrap();
+        assert!(params.check_dependencies().is_err());
+
+        let preset = TransformationParams {
+            colors: Some(16),
+            ..Default::default()
+        };
+        assert!(params.with_preset(&preset).check_dependencies().is_ok());
+    }
+}
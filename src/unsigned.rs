@@ -0,0 +1,216 @@
+use url::{Host, Origin, Url};
+
+use crate::transformation_params::{Height, TransformationParams, Width};
+
+/// Signature placeholder of unsigned URLs, e.g. `/_/w_640/{target}`.
+pub(crate) const UNSIGNED: &str = "_";
+
+/// Policy for accepting unsigned URLs, for clients which cannot sign URLs.
+///
+/// Unsigned URLs have `_` in place of the signature, e.g.
+/// `/_/w_640/https%3A%2F%2Fexample.com%2Fimage.png` or `?url=...&w=640&s=_`.
+/// They are only accepted when the target URL's origin, i.e. its scheme, host,
+/// and port, is allowed and every parameter is within the configured sets, so
+/// that arbitrary transforms cannot be requested. Beyond presets, the fit, and
+/// the output format, parameters other than width, height, and quality are not
+/// accepted.
+///
+/// # Example
+///
+/// ```rust
+/// use tower_image_xform::UnsignedPolicy;
+///
+/// let unsigned_policy = UnsignedPolicy::new()
+///     .add_host("images.example.com")
+///     .add_origin("http://localhost:8080".parse().unwrap())
+///     .set_widths(vec![640, 750, 828, 1080, 1200, 1920])
+///     .set_qualities(vec![75]);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct UnsignedPolicy {
+    origins: Vec<Origin>,
+    widths: Vec<Width>,
+    heights: Vec<Height>,
+    qualities: Vec<u8>,
+}
+
+impl UnsignedPolicy {
+    /// Create a new [`UnsignedPolicy`], which allows no origins.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow target URLs of the given host over HTTPS on its default port, e.g.
+    /// `images.example.com`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `host` is not a valid host.
+    pub fn add_host(mut self, host: impl Into<String>) -> Self {
+        let host = Host::parse(&host.into()).expect("host must be valid");
+        self.origins
+            .push(Origin::Tuple("https".to_owned(), host, 443));
+        self
+    }
+
+    /// Allow target URLs of the origin of the given URL, i.e. of the same
+    /// scheme, host, and port, e.g. `http://localhost:8080`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the URL has no host, e.g. a `data:` URL.
+    pub fn add_origin(mut self, origin: Url) -> Self {
+        let origin = origin.origin();
+        assert!(origin.is_tuple(), "origin must have a host");
+        self.origins.push(origin);
+        self
+    }
+
+    /// Set the widths which may be requested.
+    ///
+    /// Defaults to none, i.e. the width may not be set.
+    pub fn set_widths(self, widths: Vec<Width>) -> Self {
+        Self { widths, ..self }
+    }
+
+    /// Set the heights which may be requested.
+    ///
+    /// Defaults to none, i.e. the height may not be set.
+    pub fn set_heights(self, heights: Vec<Height>) -> Self {
+        Self { heights, ..self }
+    }
+
+    /// Set the qualities which may be requested.
+    ///
+    /// Defaults to none, i.e. the quality may not be set.
+    pub fn set_qualities(self, qualities: Vec<u8>) -> Self {
+        Self { qualities, ..self }
+    }
+
+    /// Returns whether an unsigned URL with the given parameters and target
+    /// URL is allowed.
+    pub(crate) fn allows(&self, params: &TransformationParams, target: &Url) -> bool {
+        let TransformationParams {
+            preset: _,
+            width,
+            height,
+            fit: _,
+            crop,
+            rotate,
+            flip,
+            gray,
+            metadata,
+            progressive,
+            colors,
+            dither,
+            quality,
+            max_bytes,
+            format: _,
+            expires,
+        } = params;
+
+        self.origins.contains(&target.origin())
+            && width.is_none_or(|width| self.widths.contains(&width))
+            && height.is_none_or(|height| self.heights.contains(&height))
+            && quality.is_none_or(|quality| self.qualities.contains(&quality))
+            && crop.is_none()
+            && rotate.is_none()
+            && flip.is_none()
+            && gray.is_none()
+            && metadata.is_none()
+            && progressive.is_none()
+            && colors.is_none()
+            && dither.is_none()
+            && max_bytes.is_none()
+            && expires.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(policy: &UnsignedPolicy, params: &str, target: &str) -> bool {
+        policy.allows(&params.parse().unwrap(), &target.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_scheme_host_and_port() {
+        let policy = UnsignedPolicy::new()
+            .add_host("Images.Example.com")
+            .add_origin("http://localhost:8080/ignored".parse().unwrap());
+
+        assert!(allows(&policy, "", "https://images.example.com/a.png"));
+        assert!(allows(&policy, "", "https://IMAGES.example.com:443/a.png"));
+        assert!(allows(&policy, "", "http://localhost:8080/a.png"));
+
+        for target in [
+            "http://images.example.com/a.png",
+            "https://images.example.com:8443/a.png",
+            "https://other.example.com/a.png",
+            "https://images.example.com.evil.example.net/a.png",
+            "https://localhost:8080/a.png",
+            "http://localhost/a.png",
+        ] {
+            assert!(!allows(&policy, "", target), "{target}");
+        }
+    }
+
+    #[test]
+    fn allows_no_origins_by_default() {
+        assert!(!allows(
+            &UnsignedPolicy::new(),
+            "",
+            "https://images.example.com/a.png"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "origin must have a host")]
+    fn rejects_origins_without_hosts() {
+        UnsignedPolicy::new().add_origin("data:image/png;base64,".parse().unwrap());
+    }
+
+    #[test]
+    fn allows_only_configured_sizes_and_qualities() {
+        let target = "https://images.example.com/a.png";
+        let policy = UnsignedPolicy::new()
+            .add_host("images.example.com")
+            .set_widths(vec![640, 1080])
+            .set_heights(vec![480])
+            .set_qualities(vec![75]);
+
+        for params in ["w_640", "w_1080,h_480,q_75", "fit_cover,f_webp", "p_thumb"] {
+            assert!(allows(&policy, params, target), "{params}");
+        }
+        for params in ["w_641", "h_640", "q_80", "w_640,q_74"] {
+            assert!(!allows(&policy, params, target), "{params}");
+        }
+
+        // Sizes and qualities may not be set at all unless configured.
+        let policy = UnsignedPolicy::new().add_host("images.example.com");
+        assert!(allows(&policy, "", target));
+        assert!(!allows(&policy, "w_640", target));
+        assert!(!allows(&policy, "q_75", target));
+    }
+
+    #[test]
+    fn rejects_other_params() {
+        let target = "https://images.example.com/a.png";
+        let policy = UnsignedPolicy::new().add_host("images.example.com");
+
+        for params in [
+            "crop_0-0-10-10",
+            "rot_90",
+            "flip_h",
+            "gray_1",
+            "md_copyright",
+            "pr_1",
+            "colors_16",
+            "maxbytes_1000",
+            "exp_1",
+        ] {
+            assert!(!allows(&policy, params, target), "{params}");
+        }
+    }
+}